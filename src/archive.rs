@@ -0,0 +1,137 @@
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// File extensions of the ROMs looked for inside archives.
+pub const ROM_EXTENSIONS: [&str; 3] = ["nes", "unf", "fds"];
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Zip(ZipError),
+    Gzip(io::Error),
+    /// The archive doesn't contain any ROM
+    NoRom,
+    /// The archive contains several ROMs and none was chosen
+    AmbiguousRom {
+        candidates: Vec<String>,
+    },
+}
+
+impl Display for ArchiveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Zip(e) => write!(f, "Invalid zip archive: {}", e),
+            ArchiveError::Gzip(e) => write!(f, "Invalid gzip archive: {}", e),
+            ArchiveError::NoRom => write!(f, "No ROM in the archive"),
+            ArchiveError::AmbiguousRom { candidates } => {
+                write!(f, "Several ROMs in the archive: {}", candidates.join(", "))
+            }
+        }
+    }
+}
+
+impl error::Error for ArchiveError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ArchiveError::Zip(e) => Some(e),
+            ArchiveError::Gzip(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ZipError> for ArchiveError {
+    fn from(e: ZipError) -> Self {
+        ArchiveError::Zip(e)
+    }
+}
+
+/// Archive formats, recognized by their magic number.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Gzip,
+}
+
+impl ArchiveFormat {
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06") {
+            Some(ArchiveFormat::Zip)
+        } else if bytes.starts_with(&[0x1F, 0x8B]) {
+            Some(ArchiveFormat::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_rom_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ROM_EXTENSIONS
+                .iter()
+                .any(|rom| extension.eq_ignore_ascii_case(rom))
+        })
+}
+
+/// Extracts the ROM of a zip or gzip archive.
+/// When a zip archive contains several ROMs, `choose` is called with their names and returns
+/// the index of the one to load, or `None` to give up.
+pub fn extract<F>(format: ArchiveFormat, bytes: &[u8], choose: F) -> Result<Vec<u8>, ArchiveError>
+where
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    match format {
+        ArchiveFormat::Zip => extract_zip(bytes, choose),
+        ArchiveFormat::Gzip => {
+            // A gzip file holds exactly one file
+            let mut rom = vec![];
+            GzDecoder::new(bytes)
+                .read_to_end(&mut rom)
+                .map_err(ArchiveError::Gzip)?;
+            Ok(rom)
+        }
+    }
+}
+
+fn extract_zip<F>(bytes: &[u8], choose: F) -> Result<Vec<u8>, ArchiveError>
+where
+    F: FnOnce(&[String]) -> Option<usize>,
+{
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+
+    // Archive indices and names of the ROMs
+    let mut indices = vec![];
+    let mut candidates = vec![];
+    for index in 0..archive.len() {
+        if let Some(name) = archive.name_for_index(index) {
+            let name = name?;
+            if !name.ends_with('/') && is_rom_name(&name) {
+                indices.push(index);
+                candidates.push(name.into_owned());
+            }
+        }
+    }
+
+    let index = match candidates.len() {
+        0 => return Err(ArchiveError::NoRom),
+        1 => indices[0],
+        _ => match choose(&candidates) {
+            Some(choice) if choice < candidates.len() => indices[choice],
+            _ => return Err(ArchiveError::AmbiguousRom { candidates }),
+        },
+    };
+
+    let mut file = archive.by_index(index)?;
+    let mut rom = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut rom)
+        .map_err(|e| ArchiveError::Zip(e.into()))?;
+    Ok(rom)
+}
@@ -0,0 +1,92 @@
+use std::env;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use nesmulator::breakpoints::parse_number;
+use nesmulator::cartridge::Cartridge;
+use nesmulator::database::RomDatabase;
+use nesmulator::debugger::Debugger;
+use nesmulator::gdb;
+use nesmulator::nes::Nes;
+
+const USAGE: &str = "Usage: nesdbg <rom> [--pc <address>] [--database <xml>] [--gdb <port>]
+  --pc <address>  start at address instead of the reset vector
+  --database <xml>
+                  correct the header with a NES 2.0 XML database, the complete
+                  nes20db.xml for real dumps: the built-in one only knows nestest
+  --gdb <port>    wait for a GDB remote protocol client on localhost:port
+                  instead of reading commands";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let mut path = None;
+    let mut pc = None;
+    let mut gdb_port = None;
+    let mut database = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pc" => pc = args.next(),
+            "--database" => database = Some(args.next().unwrap_or_else(|| usage())),
+            "--gdb" => gdb_port = Some(args.next().and_then(|port| port.parse().ok())),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let cartridge = match database {
+        Some(database) => {
+            Cartridge::from_file_with_database(path, &RomDatabase::from_file(database)?)?
+        }
+        None => Cartridge::from_file(path)?,
+    };
+    let mut debugger = Debugger::new(Nes::new(cartridge));
+    // e.g. $C000 for the automated mode of nestest
+    if let Some(pc) = pc {
+        debugger.nes.cpu.pc = parse_number(&pc).ok_or("invalid --pc address")?;
+    }
+    if let Some(port) = gdb_port {
+        let port = port.unwrap_or_else(|| usage());
+        println!("Waiting for a GDB client on localhost:{}", port);
+        let result = gdb::listen(&mut debugger.nes, port);
+        debugger.nes.save()?;
+        return Ok(result?);
+    }
+    println!("=> {}", debugger.nes.cpu.disassemble(debugger.nes.cpu.pc));
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut last_command = String::new();
+    loop {
+        print!("(nesdbg) ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        // An empty line repeats the last command, like in GDB
+        let command = match line.trim() {
+            "" => last_command.clone(),
+            command => command.to_string(),
+        };
+        if command == "quit" || command == "q" {
+            break;
+        }
+
+        match debugger.execute(&command) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(e) => println!("{}", e),
+        }
+        last_command = command;
+    }
+    // The battery backed RAM survives until the next session
+    debugger.nes.save()?;
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
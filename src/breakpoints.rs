@@ -0,0 +1,492 @@
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::ops::RangeInclusive;
+
+use crate::util::BitOperations;
+
+/// A bus access made by the CPU, or the execution of an instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Execute,
+    Read,
+    Write,
+}
+
+/// What a breakpoint stops on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Before an instruction in the range runs
+    Execute,
+    /// Watchpoints, after the access. Dummy accesses count, like for the chips on the bus.
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl BreakpointKind {
+    fn matches(self, access: Access) -> bool {
+        matches!(
+            (self, access),
+            (BreakpointKind::Execute, Access::Execute)
+                | (BreakpointKind::Read, Access::Read)
+                | (BreakpointKind::Write, Access::Write)
+                | (BreakpointKind::ReadWrite, Access::Read)
+                | (BreakpointKind::ReadWrite, Access::Write)
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub kind: BreakpointKind,
+    pub addresses: RangeInclusive<u16>,
+    /// Only hits when the condition is true
+    pub condition: Option<Condition>,
+    /// Number of hits ignored before stopping
+    pub ignore_count: u64,
+    pub enabled: bool,
+    /// Number of times the breakpoint was hit, ignored hits included
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(kind: BreakpointKind, addresses: RangeInclusive<u16>) -> Self {
+        Self {
+            kind,
+            addresses,
+            condition: None,
+            ignore_count: 0,
+            enabled: true,
+            hits: 0,
+        }
+    }
+
+    pub fn execute(address: u16) -> Self {
+        Self::new(BreakpointKind::Execute, address..=address)
+    }
+}
+
+/// A breakpoint that stopped the CPU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BreakpointHit {
+    pub id: usize,
+    pub access: Access,
+    pub address: u16,
+    /// Value read or written, the opcode for execute breakpoints
+    pub value: u8,
+}
+
+/// The breakpoints of a CPU, identified by the number returned by `add`.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+
+    // Cached so the CPU only checks a bool when nothing is set
+    execute: bool,
+    read: bool,
+    write: bool,
+}
+
+impl Breakpoints {
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push((id, breakpoint));
+        self.update();
+        id
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Breakpoint> {
+        let index = self.breakpoints.iter().position(|(i, _)| *i == id)?;
+        let (_, breakpoint) = self.breakpoints.remove(index);
+        self.update();
+        Some(breakpoint)
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.update();
+    }
+
+    /// Returns false if there is no breakpoint `id`.
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        let found = match self.breakpoints.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, breakpoint)) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        };
+        self.update();
+        found
+    }
+
+    /// Returns false if there is no breakpoint `id`.
+    pub fn set_ignore_count(&mut self, id: usize, count: u64) -> bool {
+        match self.breakpoints.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, breakpoint)) => {
+                breakpoint.ignore_count = count;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.breakpoints
+            .iter()
+            .find(|(i, _)| *i == id)
+            .map(|(_, breakpoint)| breakpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty()
+    }
+
+    /// True if an enabled breakpoint may stop on `access`.
+    #[inline]
+    pub fn watches(&self, access: Access) -> bool {
+        match access {
+            Access::Execute => self.execute,
+            Access::Read => self.read,
+            Access::Write => self.write,
+        }
+    }
+
+    /// Counts the hits of the breakpoints matching the access,
+    /// returns the first one that stops the CPU.
+    pub fn check(
+        &mut self,
+        access: Access,
+        address: u16,
+        registers: &Registers,
+    ) -> Option<BreakpointHit> {
+        let mut stop = None;
+        for (id, breakpoint) in self.breakpoints.iter_mut() {
+            if !breakpoint.enabled
+                || !breakpoint.kind.matches(access)
+                || !breakpoint.addresses.contains(&address)
+            {
+                continue;
+            }
+            if let Some(condition) = &breakpoint.condition {
+                if !condition.evaluate(registers) {
+                    continue;
+                }
+            }
+
+            breakpoint.hits += 1;
+            if breakpoint.hits > breakpoint.ignore_count && stop.is_none() {
+                stop = Some(BreakpointHit {
+                    id: *id,
+                    access,
+                    address,
+                    value: registers.value,
+                });
+            }
+        }
+        stop
+    }
+
+    fn update(&mut self) {
+        let breakpoints = &self.breakpoints;
+        let enabled = |kind| {
+            breakpoints
+                .iter()
+                .any(|(_, breakpoint)| breakpoint.enabled && breakpoint.kind.matches(kind))
+        };
+        self.execute = enabled(Access::Execute);
+        self.read = enabled(Access::Read);
+        self.write = enabled(Access::Write);
+    }
+}
+
+/// What conditions can look at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub pc: u16,
+    pub p: u8,
+    /// Value read or written, the opcode for execute breakpoints
+    pub value: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConditionError {
+    /// Byte offset in the expression
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl Display for ConditionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid condition, column {}: {}",
+            self.position + 1,
+            self.message
+        )
+    }
+}
+
+impl error::Error for ConditionError {}
+
+/// An expression on the registers, e.g. `a == $10 && !c || x >= 3`.
+///
+/// Operands are numbers ($FF, 0xFF or 255), the registers a, x, y, s, pc and p,
+/// the flags c, z, i, d, v and n, and `value`, the byte read or written.
+/// Operators are == != < <= > >=, && ||, ! and parentheses. Non-zero values are true.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expression: Expression,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, ConditionError> {
+        let mut parser = Parser {
+            source,
+            position: 0,
+        };
+        let expression = parser.or()?;
+        parser.skip_whitespace();
+        if parser.position < source.len() {
+            return Err(parser.error("unexpected character"));
+        }
+        Ok(Self {
+            source: source.trim().to_string(),
+            expression,
+        })
+    }
+
+    pub fn evaluate(&self, registers: &Registers) -> bool {
+        self.expression.evaluate(registers) != 0
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Operand {
+    A,
+    X,
+    Y,
+    S,
+    Pc,
+    P,
+    Value,
+    /// Bit of P
+    Flag(u8),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expression {
+    Number(u16),
+    Operand(Operand),
+    Not(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    fn evaluate(&self, registers: &Registers) -> u16 {
+        match self {
+            Expression::Number(n) => *n,
+            Expression::Operand(operand) => match operand {
+                Operand::A => registers.a as u16,
+                Operand::X => registers.x as u16,
+                Operand::Y => registers.y as u16,
+                Operand::S => registers.s as u16,
+                Operand::Pc => registers.pc,
+                Operand::P => registers.p as u16,
+                Operand::Value => registers.value as u16,
+                Operand::Flag(bit) => registers.p.get_bit(*bit) as u16,
+            },
+            Expression::Not(e) => (e.evaluate(registers) == 0) as u16,
+            Expression::Binary(operator, left, right) => {
+                let left = left.evaluate(registers);
+                // && and || short-circuit
+                let result = match operator {
+                    Operator::And => left != 0 && right.evaluate(registers) != 0,
+                    Operator::Or => left != 0 || right.evaluate(registers) != 0,
+                    Operator::Equal => left == right.evaluate(registers),
+                    Operator::NotEqual => left != right.evaluate(registers),
+                    Operator::Less => left < right.evaluate(registers),
+                    Operator::LessOrEqual => left <= right.evaluate(registers),
+                    Operator::Greater => left > right.evaluate(registers),
+                    Operator::GreaterOrEqual => left >= right.evaluate(registers),
+                };
+                result as u16
+            }
+        }
+    }
+}
+
+/// Recursive descent parser, from the lowest precedence:
+/// or := and ("||" and)*
+/// and := comparison ("&&" comparison)*
+/// comparison := unary (("==" | "!=" | "<=" | ">=" | "<" | ">") unary)?
+/// unary := "!" unary | "(" or ")" | number | name
+struct Parser<'a> {
+    source: &'a str,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> ConditionError {
+        ConditionError {
+            position: self.position,
+            message,
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.source[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consumes `token` if it comes next.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expression, ConditionError> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            let right = self.and()?;
+            left = Expression::Binary(Operator::Or, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expression, ConditionError> {
+        let mut left = self.comparison()?;
+        while self.eat("&&") {
+            let right = self.comparison()?;
+            left = Expression::Binary(Operator::And, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expression, ConditionError> {
+        let left = self.unary()?;
+        // Two character operators first
+        let operators = [
+            ("==", Operator::Equal),
+            ("!=", Operator::NotEqual),
+            ("<=", Operator::LessOrEqual),
+            (">=", Operator::GreaterOrEqual),
+            ("<", Operator::Less),
+            (">", Operator::Greater),
+        ];
+        for (token, operator) in operators {
+            if self.eat(token) {
+                let right = self.unary()?;
+                return Ok(Expression::Binary(
+                    operator,
+                    Box::new(left),
+                    Box::new(right),
+                ));
+            }
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, ConditionError> {
+        if self.eat("!") {
+            return Ok(Expression::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expression = self.or()?;
+            if !self.eat(")") {
+                return Err(self.error("expected ')'"));
+            }
+            return Ok(expression);
+        }
+
+        self.skip_whitespace();
+        let start = self.position;
+        let length = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '$' || c == '_'))
+            .unwrap_or(self.rest().len());
+        if length == 0 {
+            return Err(self.error("expected a number or a register"));
+        }
+        let word = &self.source[start..start + length];
+        let expression = parse_word(word).ok_or(ConditionError {
+            position: start,
+            message: "unknown register or invalid number",
+        })?;
+        self.position += length;
+        Ok(expression)
+    }
+}
+
+fn parse_word(word: &str) -> Option<Expression> {
+    let operand = match word.to_ascii_lowercase().as_str() {
+        "a" => Operand::A,
+        "x" => Operand::X,
+        "y" => Operand::Y,
+        "s" | "sp" => Operand::S,
+        "pc" => Operand::Pc,
+        "p" => Operand::P,
+        "value" => Operand::Value,
+        "c" => Operand::Flag(0),
+        "z" => Operand::Flag(1),
+        "i" => Operand::Flag(2),
+        "d" => Operand::Flag(3),
+        "v" => Operand::Flag(6),
+        "n" => Operand::Flag(7),
+        _ => return parse_number(word).map(Expression::Number),
+    };
+    Some(Expression::Operand(operand))
+}
+
+/// $FF, 0xFF or 255.
+pub fn parse_number(word: &str) -> Option<u16> {
+    if let Some(hex) = word
+        .strip_prefix('$')
+        .or_else(|| word.strip_prefix("0x"))
+        .or_else(|| word.strip_prefix("0X"))
+    {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        word.parse().ok()
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt::Debug;
+
+use crate::cartridge::Cartridge;
+use crate::ppu::PPU;
+
+/// Everything the CPU can reach through its address and data pins.
+pub trait Bus: Debug {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Reads a byte without any side effect (no register reads, no open bus update).
+    /// Used by the disassembler and debugging tools.
+    fn peek(&self, address: u16) -> u8;
+
+    /// Called once per CPU cycle so the other chips on the bus can advance.
+    fn tick(&mut self) {}
+
+    /// Returns true when a chip signaled an NMI since the last poll.
+    fn poll_nmi(&mut self) -> bool {
+        false
+    }
+
+    /// State of the shared IRQ line, true when at least one chip asserts it.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Called when the CPU is reset, before the reset sequence.
+    fn reset(&mut self) {}
+
+    /// True while a DMA halts the CPU, which then calls `dma_cycle` instead of fetching
+    /// its next opcode.
+    fn dma_active(&self) -> bool {
+        false
+    }
+
+    /// Runs one cycle of the DMA that halts the CPU.
+    fn dma_cycle(&mut self) {}
+}
+
+/// A chip mapped into one of the regions of the system bus.
+pub trait Device: Debug {
+    /// Returns `None` if nothing drives the data bus (open bus).
+    fn read(&mut self, address: u16) -> Option<u8>;
+    fn write(&mut self, address: u16, value: u8);
+
+    fn peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+}
+
+/// Nothing is connected, reads return the open bus value and writes are lost.
+#[derive(Debug)]
+pub struct Unmapped;
+
+impl Device for Unmapped {
+    fn read(&mut self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn write(&mut self, _address: u16, _value: u8) {}
+}
+
+/// Content of the internal RAM at power on. It isn't initialized by the hardware
+/// and some games depend on what it usually contains.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    Ones,
+    /// Pseudo random bytes, the same for a given seed
+    Random {
+        seed: u64,
+    },
+    /// Alternating blocks of 4 bytes of $00 and $FF, common on front loaders
+    Console,
+}
+
+impl RamInit {
+    pub fn fill(&self, ram: &mut [u8]) {
+        match *self {
+            RamInit::Zeros => ram.fill(0x00),
+            RamInit::Ones => ram.fill(0xFF),
+            RamInit::Random { seed } => {
+                // xorshift64*, the state must not be 0
+                let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15).max(1);
+                for byte in ram.iter_mut() {
+                    state ^= state >> 12;
+                    state ^= state << 25;
+                    state ^= state >> 27;
+                    *byte = (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8;
+                }
+            }
+            RamInit::Console => {
+                for (i, byte) in ram.iter_mut().enumerate() {
+                    *byte = if i & 4 == 0 { 0x00 } else { 0xFF };
+                }
+            }
+        }
+    }
+}
+
+/// The NES CPU bus:
+/// $0000-$1FFF  2 KiB internal RAM, mirrored 4 times
+/// $2000-$3FFF  PPU registers
+/// $4000-$401F  APU and I/O registers
+/// $4020-$FFFF  Cartridge space
+#[derive(Debug)]
+pub struct SystemBus {
+    pub ram: [u8; 0x800],
+    pub ppu: PPU,
+    pub io: Box<dyn Device>,
+    pub cartridge: Cartridge,
+
+    /// Last value seen on the data bus, returned when reading unmapped addresses.
+    open_bus: u8,
+    oam_dma: Option<OamDma>,
+    /// The DMA reads on get cycles and writes on put cycles, they alternate
+    put_cycle: bool,
+}
+
+/// A transfer started by writing to $4014.
+#[derive(Debug)]
+struct OamDma {
+    page: u8,
+    /// False until the cycle spent halting the CPU has run
+    halted: bool,
+    /// Number of bytes copied
+    count: u16,
+    /// The byte read on the last get cycle, written on the following put cycle
+    value: Option<u8>,
+}
+
+impl SystemBus {
+    pub fn new(cartridge: Cartridge) -> Self {
+        Self::with_ram_init(cartridge, RamInit::default())
+    }
+
+    pub fn with_ram_init(cartridge: Cartridge, ram_init: RamInit) -> Self {
+        let mut ram = [0; 0x800];
+        ram_init.fill(&mut ram);
+        Self {
+            ram,
+            ppu: PPU::new(),
+            io: Box::new(Unmapped),
+            cartridge,
+            open_bus: 0,
+            oam_dma: None,
+            put_cycle: false,
+        }
+    }
+
+    /// Starts copying the 256 bytes of page $XX00-$XXFF into OAM. The CPU is halted
+    /// for 513 cycles, or 514 when an extra cycle is needed to read on a get cycle.
+    fn oam_dma(&mut self, page: u8) {
+        self.oam_dma = Some(OamDma {
+            page,
+            halted: false,
+            count: 0,
+            value: None,
+        });
+    }
+}
+
+impl Bus for SystemBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = match address {
+            0x0000..=0x1FFF => Some(self.ram[address as usize & 0x07FF]),
+            0x2000..=0x3FFF => Some(self.ppu.read_register(address, &mut self.cartridge)),
+            0x4000..=0x401F => self.io.read(address),
+            0x4020..=0xFFFF => self.cartridge.read(address),
+        };
+        self.open_bus = value.unwrap_or(self.open_bus);
+        self.open_bus
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.open_bus = value;
+        match address {
+            0x0000..=0x1FFF => self.ram[address as usize & 0x07FF] = value,
+            0x2000..=0x3FFF => self.ppu.write_register(address, value, &mut self.cartridge),
+            0x4014 => self.oam_dma(value),
+            0x4000..=0x401F => self.io.write(address, value),
+            0x4020..=0xFFFF => self.cartridge.write(address, value),
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        let value = match address {
+            0x0000..=0x1FFF => Some(self.ram[address as usize & 0x07FF]),
+            0x2000..=0x3FFF => Some(self.ppu.peek_register(address)),
+            0x4000..=0x401F => self.io.peek(address),
+            0x4020..=0xFFFF => self.cartridge.read(address),
+        };
+        value.unwrap_or(self.open_bus)
+    }
+
+    fn tick(&mut self) {
+        self.put_cycle = !self.put_cycle;
+        self.cartridge.clock();
+        for _ in 0..3 {
+            self.ppu.clock(&mut self.cartridge);
+        }
+    }
+
+    fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+
+    fn irq(&self) -> bool {
+        self.cartridge.irq()
+    }
+
+    fn reset(&mut self) {
+        // The APU channels are silenced, like writing 0 to $4015
+        self.io.write(0x4015, 0x00);
+    }
+
+    fn dma_active(&self) -> bool {
+        self.oam_dma.is_some()
+    }
+
+    fn dma_cycle(&mut self) {
+        let mut dma = match self.oam_dma.take() {
+            Some(dma) => dma,
+            None => return,
+        };
+        if !dma.halted {
+            dma.halted = true;
+        } else if let Some(value) = dma.value.take() {
+            self.ppu.write_oam(value);
+            dma.count += 1;
+            if dma.count == 256 {
+                return;
+            }
+        } else if !self.put_cycle {
+            let address = u16::from_le_bytes([dma.count as u8, dma.page]);
+            dma.value = Some(self.read(address));
+        }
+        // Otherwise this is the alignment cycle, the first read waits for a get cycle
+        self.oam_dma = Some(dma);
+    }
+}
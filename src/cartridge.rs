@@ -1,106 +1,502 @@
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::{error, fs, io};
 
-use crate::mapper::{Mapper, Mapper0};
-use crate::util::BitOperations;
+use crate::archive::{self, ArchiveError, ArchiveFormat};
+use crate::checksum::{crc32, sha1};
+use crate::database::{HeaderField, RomDatabase};
+use crate::header::RomHeader;
+use crate::mapper::{
+    mapper_name, Mapper, Mapper0, Mapper1, Mapper2, Mapper3, Mapper4, Mapper7, Mmc3Revision,
+};
+use crate::unif::Unif;
+use crate::util::Units;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    Archive(ArchiveError),
+    /// The file doesn't start with "NES\x1A"
+    BadMagic,
+    /// The file is shorter than the 16 byte header
+    TruncatedHeader,
+    TruncatedTrainer,
+    TruncatedPrgRom {
+        expected: usize,
+        actual: usize,
+    },
+    TruncatedChrRom {
+        expected: usize,
+        actual: usize,
+    },
+    /// The header declares no PRG ROM at all
+    MissingPrgRom,
+    UnsupportedMapper {
+        number: u16,
+        name: &'static str,
+    },
+    InvalidNes2Field {
+        field: &'static str,
+        value: u8,
+    },
+    /// A UNIF chunk is longer than the rest of the file
+    TruncatedChunk(String),
+    /// A required UNIF chunk is missing
+    MissingChunk(&'static str),
+    /// A UNIF board name that doesn't map to any of our mappers
+    UnsupportedBoard(String),
+}
+
+impl Display for CartridgeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "I/O error: {}", e),
+            CartridgeError::Archive(e) => e.fmt(f),
+            CartridgeError::BadMagic => write!(f, "Not a NES file"),
+            CartridgeError::TruncatedHeader => write!(f, "Truncated header"),
+            CartridgeError::TruncatedTrainer => write!(f, "Truncated trainer"),
+            CartridgeError::TruncatedPrgRom { expected, actual } => write!(
+                f,
+                "Truncated PRG ROM, expected {} bytes but got {}",
+                expected, actual
+            ),
+            CartridgeError::TruncatedChrRom { expected, actual } => write!(
+                f,
+                "Truncated CHR ROM, expected {} bytes but got {}",
+                expected, actual
+            ),
+            CartridgeError::MissingPrgRom => write!(f, "No PRG ROM"),
+            CartridgeError::UnsupportedMapper { number, name } => {
+                write!(f, "Unsupported mapper {} ({})", number, name)
+            }
+            CartridgeError::InvalidNes2Field { field, value } => {
+                write!(f, "Invalid NES 2.0 {}: ${:02X}", field, value)
+            }
+            CartridgeError::TruncatedChunk(id) => write!(f, "Truncated UNIF chunk {}", id),
+            CartridgeError::MissingChunk(id) => write!(f, "Missing UNIF chunk {}", id),
+            CartridgeError::UnsupportedBoard(board) => write!(f, "Unsupported board {}", board),
+        }
+    }
+}
+
+impl error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CartridgeError::Io(e) => Some(e),
+            CartridgeError::Archive(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        CartridgeError::Io(e)
+    }
+}
+
+impl From<ArchiveError> for CartridgeError {
+    fn from(e: ArchiveError) -> Self {
+        CartridgeError::Archive(e)
+    }
+}
+
+/// How the 4 logical nametables at $2000, $2400, $2800 and $2C00 are wired to physical VRAM.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mirroring {
+    /// $2000 = $2400 and $2800 = $2C00 (vertical arrangement)
+    Horizontal,
+    /// $2000 = $2800 and $2400 = $2C00 (horizontal arrangement)
+    Vertical,
+    /// All nametables use the first KiB of CIRAM
+    SingleScreenA,
+    /// All nametables use the second KiB of CIRAM
+    SingleScreenB,
+    /// The cartridge provides 2 extra KiB of VRAM, no mirroring
+    FourScreen,
+}
+
+impl Mirroring {
+    /// Maps a nametable address ($2000-$2FFF) to an offset in 4 KiB of nametable memory,
+    /// the first 2 KiB being CIRAM and the last 2 KiB being the cartridge's VRAM.
+    pub fn nametable_address(self, address: u16) -> usize {
+        let nametable = (address as usize >> 10) & 0x03;
+        let offset = address as usize & 0x03FF;
+        let page = match self {
+            Mirroring::Horizontal => nametable >> 1,
+            Mirroring::Vertical => nametable & 0x01,
+            Mirroring::SingleScreenA => 0,
+            Mirroring::SingleScreenB => 1,
+            Mirroring::FourScreen => nametable,
+        };
+        page * 0x400 + offset
+    }
+}
 
 #[derive(Debug)]
 pub struct Cartridge {
     prg_rom: Vec<u8>,
-    chr_rom: Vec<u8>,
+    /// CHR ROM, or CHR RAM if the cartridge has no CHR ROM
+    chr: Vec<u8>,
+    is_chr_ram: bool,
+    /// Extra nametable RAM for four-screen boards
+    vram: Vec<u8>,
+    /// Hardwired nametable arrangement, the mapper can override it
+    mirroring: Mirroring,
+    /// Work RAM at $6000-$7FFF
+    prg_ram: Vec<u8>,
+    /// 512 bytes loaded at $7000 on power on
+    trainer: Option<Vec<u8>>,
+    /// Where the battery backed PRG RAM is persisted
+    save_path: Option<PathBuf>,
     mapper: Box<dyn Mapper>,
+    /// The header, as corrected by the ROM database
+    header: RomHeader,
+    corrected_fields: Vec<HeaderField>,
+    /// Checksums of the PRG ROM followed by the CHR ROM
+    crc32: u32,
+    sha1: [u8; 20],
 }
 
 impl Cartridge {
-    pub fn from_file<P: AsRef<Path>>(filepath: P) -> Self {
-        let buffer = {
-            let mut file = File::open(filepath).unwrap();
-            let mut buffer = vec![];
-            file.read_to_end(&mut buffer).unwrap();
-            buffer
-        };
-        let (header, data) = buffer.split_at(16);
+    /// Loads a ROM, or the only ROM of a zip or gzip archive.
+    pub fn from_file<P: AsRef<Path>>(filepath: P) -> Result<Self, CartridgeError> {
+        Self::from_file_with(filepath, |_| None)
+    }
 
-        if !header.starts_with(b"NES\x1A") {
-            panic!("Not a NES file.");
-        }
+    /// Like `from_file`, `choose` picks the ROM when a zip archive contains several.
+    /// It gets their names and returns the index of the one to load.
+    pub fn from_file_with<P, F>(filepath: P, choose: F) -> Result<Self, CartridgeError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&[String]) -> Option<usize>,
+    {
+        Self::load(filepath, choose, RomDatabase::builtin())
+    }
 
-        let prg_rom_size = {
-            let lsb = header[4];
-            let msb = header[9].get_bits(0..=3);
+    /// Like `from_file`, the header is corrected with `database` instead of the built-in one.
+    pub fn from_file_with_database<P: AsRef<Path>>(
+        filepath: P,
+        database: &RomDatabase,
+    ) -> Result<Self, CartridgeError> {
+        Self::load(filepath, |_| None, database)
+    }
 
-            if msb == 0xF {
-                let multiplier = lsb.get_bits(0..=1);
-                let exponent = lsb.get_bits(2..=7);
-                2usize.pow(exponent as u32) * (multiplier as usize * 2 + 1)
-            } else {
-                let mut size = 0u16;
-                size.set_bits(0..=7, lsb as u16);
-                size.set_bits(8..=11, msb as u16);
-                size as usize * 16 * 1024
+    fn load<P, F>(filepath: P, choose: F, database: &RomDatabase) -> Result<Self, CartridgeError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&[String]) -> Option<usize>,
+    {
+        let mut buffer = fs::read(&filepath)?;
+        if let Some(format) = ArchiveFormat::detect(&buffer) {
+            buffer = archive::extract(format, &buffer, choose)?;
+        }
+        let mut cartridge = Self::from_bytes_with_database(&buffer, database)?;
+
+        if cartridge.header.has_battery {
+            let save_path = filepath.as_ref().with_extension("sav");
+            // No save file yet is fine
+            if let Ok(save) = fs::read(&save_path) {
+                let len = save.len().min(cartridge.prg_ram.len());
+                cartridge.prg_ram[..len].copy_from_slice(&save[..len]);
             }
-        };
+            cartridge.save_path = Some(save_path);
+            // The trainer is loaded over the save
+            cartridge.power_on();
+        }
+
+        Ok(cartridge)
+    }
 
-        let chr_rom_size = {
-            let lsb = header[5];
-            let msb = header[9].get_bits(4..=7);
+    /// Loads an iNES or UNIF image, the header is corrected with the built-in ROM database.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        Self::from_bytes_with_database(bytes, RomDatabase::builtin())
+    }
 
-            if msb == 0xF {
-                let multiplier = lsb.get_bits(0..=1);
-                let exponent = lsb.get_bits(2..=7);
-                2usize.pow(exponent as u32) * (multiplier as usize * 2 + 1)
-            } else {
-                let mut size = 0u16;
-                size.set_bits(0..=7, lsb as u16);
-                size.set_bits(8..=11, msb as u16);
-                size as usize * 8 * 1024
+    pub fn from_bytes_with_database(
+        bytes: &[u8],
+        database: &RomDatabase,
+    ) -> Result<Self, CartridgeError> {
+        if bytes.starts_with(Unif::MAGIC) {
+            let unif = Unif::parse(bytes)?;
+            return Self::new(unif.header, None, &unif.prg_rom, &unif.chr_rom, database);
+        }
+
+        let header = RomHeader::parse(bytes)?;
+        let data = &bytes[RomHeader::SIZE..];
+
+        let prg_rom_size = header.prg_rom_size;
+        let chr_rom_size = header.chr_rom_size;
+
+        let (trainer, data) = if header.has_trainer {
+            if data.len() < 512 {
+                return Err(CartridgeError::TruncatedTrainer);
             }
+            let (trainer, data) = data.split_at(512);
+            (Some(trainer.to_vec()), data)
+        } else {
+            (None, data)
         };
 
-        let mapper_number = {
-            let mut n = 0u16;
-            n.set_bits(0..=3, header[6].get_bits(4..=7) as u16);
-            n.set_bits(4..=7, header[7].get_bits(4..=7) as u16);
-            n.set_bits(8..=11, header[8].get_bits(0..=3) as u16);
-            n
+        if data.len() < prg_rom_size {
+            return Err(CartridgeError::TruncatedPrgRom {
+                expected: prg_rom_size,
+                actual: data.len(),
+            });
+        }
+        let (prg_rom, cdr) = data.split_at(prg_rom_size);
+
+        if cdr.len() < chr_rom_size {
+            return Err(CartridgeError::TruncatedChrRom {
+                expected: chr_rom_size,
+                actual: cdr.len(),
+            });
+        }
+        let (chr_rom, _cdr) = cdr.split_at(chr_rom_size);
+
+        Self::new(header, trainer, prg_rom, chr_rom, database)
+    }
+
+    /// Builds the cartridge described by `header`, whatever the file format was.
+    fn new(
+        mut header: RomHeader,
+        trainer: Option<Vec<u8>>,
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+        database: &RomDatabase,
+    ) -> Result<Self, CartridgeError> {
+        let rom = [prg_rom, chr_rom].concat();
+        let (crc32, sha1) = (crc32(&rom), sha1(&rom));
+        let corrected_fields = match database.find(crc32, &sha1) {
+            Some(game) => game.correct(&mut header),
+            None => vec![],
         };
 
-        let is_trainer_present = header[6].get_bit(2);
+        let mut prg_ram_size = header.prg_ram_size() + header.prg_nvram_size();
+        if trainer.is_some() {
+            // The trainer needs PRG RAM up to $71FF
+            prg_ram_size = prg_ram_size.max(8.KiB());
+        }
+        let mirroring = header.mirroring;
 
-        // Skip useless trainer
-        let data = if is_trainer_present {
-            &data[512..]
+        let is_chr_ram = chr_rom.is_empty();
+        let chr = if is_chr_ram {
+            // Some NES 2.0 headers don't declare the CHR RAM size
+            let chr_ram_size = header.chr_ram_size() + header.chr_nvram_size();
+            vec![0; chr_ram_size.max(8.KiB())]
         } else {
-            &data
+            chr_rom.to_vec()
         };
 
-        let (prg_rom, cdr) = data.split_at(prg_rom_size);
-        let (chr_rom, _cdr) = cdr.split_at(chr_rom_size);
+        let mapper: Box<dyn Mapper> = match header.mapper {
+            0 => Box::new(Mapper0::new(prg_rom.len())),
+            1 => Box::new(Mapper1::new(prg_rom.len(), chr.len(), prg_ram_size)),
+            2 => Box::new(Mapper2::new(
+                prg_rom.len(),
+                has_bus_conflicts(header.mapper, header.submapper),
+            )),
+            3 => Box::new(Mapper3::new(
+                prg_rom.len(),
+                chr.len(),
+                has_bus_conflicts(header.mapper, header.submapper),
+            )),
+            4 => {
+                // Submapper 4 is the MMC3A and its old IRQ behavior
+                let revision = if header.submapper == 4 {
+                    Mmc3Revision::Nec
+                } else {
+                    Mmc3Revision::Sharp
+                };
+                Box::new(Mapper4::new(prg_rom.len(), chr.len(), revision))
+            }
+            7 => Box::new(Mapper7::new(
+                prg_rom.len(),
+                has_bus_conflicts(header.mapper, header.submapper),
+            )),
+            number => {
+                return Err(CartridgeError::UnsupportedMapper {
+                    number,
+                    name: mapper_name(number),
+                })
+            }
+        };
 
-        Self {
+        let mut cartridge = Self {
             prg_rom: prg_rom.to_vec(),
-            chr_rom: chr_rom.to_vec(),
-            mapper: {
-                match mapper_number {
-                    0 => Box::new(Mapper0::new(prg_rom.len())),
-                    _ => unimplemented!(),
-                }
+            chr,
+            is_chr_ram,
+            vram: if mirroring == Mirroring::FourScreen {
+                vec![0; 2.KiB()]
+            } else {
+                vec![]
             },
+            mirroring,
+            prg_ram: vec![0; prg_ram_size],
+            trainer,
+            save_path: None,
+            mapper,
+            header,
+            corrected_fields,
+            crc32,
+            sha1,
+        };
+        cartridge.power_on();
+
+        Ok(cartridge)
+    }
+
+    /// Loads the trainer, if any, into PRG RAM at $7000-$71FF.
+    pub fn power_on(&mut self) {
+        if let Some(trainer) = &self.trainer {
+            self.prg_ram[0x1000..0x1200].copy_from_slice(trainer);
         }
     }
-}
 
-impl std::ops::Index<u16> for Cartridge {
-    type Output = u8;
+    pub fn trainer(&self) -> Option<&[u8]> {
+        self.trainer.as_deref()
+    }
+
+    pub fn header(&self) -> &RomHeader {
+        &self.header
+    }
+
+    /// Header fields that were wrong according to the ROM database.
+    pub fn corrected_fields(&self) -> &[HeaderField] {
+        &self.corrected_fields
+    }
+
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn sha1(&self) -> [u8; 20] {
+        self.sha1
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.header.has_battery
+    }
+
+    pub fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    /// Writes the battery backed PRG RAM next to the ROM, does nothing without a battery.
+    pub fn save(&self) -> io::Result<()> {
+        match &self.save_path {
+            Some(save_path) => fs::write(save_path, &self.prg_ram),
+            None => Ok(()),
+        }
+    }
+
+    /// PRG ROM smaller than what the mapper banks, like 8 KiB on NROM, is mirrored.
+    fn prg_rom_address(&self, address: u16) -> usize {
+        self.mapper.prg_address(address) % self.prg_rom.len()
+    }
+
+    /// CPU side, $4020-$FFFF. Returns `None` for open bus.
+    pub fn read(&self, address: u16) -> Option<u8> {
+        match address {
+            0x6000..=0x7FFF => self.prg_ram_address(address).map(|a| self.prg_ram[a]),
+            0x8000..=0xFFFF => Some(self.prg_rom[self.prg_rom_address(address)]),
+            _ => None,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF => {
+                if let Some(address) = self.prg_ram_address(address) {
+                    if self.mapper.is_prg_ram_writable() {
+                        self.prg_ram[address] = value;
+                    }
+                }
+            }
+            0x8000..=0xFFFF => {
+                let value = if self.mapper.has_bus_conflicts() {
+                    value & self.prg_rom[self.prg_rom_address(address)]
+                } else {
+                    value
+                };
+                self.mapper.write(address, value);
+            }
+            _ => {}
+        }
+    }
+
+    fn prg_ram_address(&self, address: u16) -> Option<usize> {
+        if !(0x6000..=0x7FFF).contains(&address) || self.prg_ram.is_empty() {
+            return None;
+        }
+        self.mapper
+            .prg_ram_address(address)
+            .map(|address| address % self.prg_ram.len())
+    }
+
+    /// Called once per CPU cycle.
+    pub fn clock(&mut self) {
+        self.mapper.cpu_clock();
+    }
+
+    /// State of the IRQ line of the cartridge.
+    pub fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+
+    /// PPU side, pattern tables at $0000-$1FFF
+    pub fn ppu_read(&mut self, address: u16) -> u8 {
+        self.mapper.ppu_address(address);
+        self.chr[self.chr_address(address)]
+    }
+
+    pub fn ppu_write(&mut self, address: u16, value: u8) {
+        self.mapper.ppu_address(address);
+        if self.is_chr_ram {
+            let address = self.chr_address(address);
+            self.chr[address] = value;
+        }
+    }
+
+    /// CHR smaller than what the mapper banks, like 4 KiB on NROM, is mirrored.
+    fn chr_address(&self, address: u16) -> usize {
+        self.mapper.chr_address(address) % self.chr.len()
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        // Four-screen VRAM is hardwired on the board
+        if self.mirroring == Mirroring::FourScreen {
+            return Mirroring::FourScreen;
+        }
+        self.mapper.mirroring().unwrap_or(self.mirroring)
+    }
 
-    fn index(&self, address: u16) -> &Self::Output {
-        &self.prg_rom[self.mapper.address(address) as usize]
+    /// Nametables, $2000-$2FFF. They live either in the console's 2 KiB of VRAM (CIRAM)
+    /// or in the cartridge's own VRAM.
+    pub fn read_nametable(&mut self, address: u16, ciram: &[u8]) -> u8 {
+        self.mapper.ppu_address(address);
+        match self.mirroring().nametable_address(address) {
+            address if address < ciram.len() => ciram[address],
+            address => self.vram[address - ciram.len()],
+        }
+    }
+
+    pub fn write_nametable(&mut self, address: u16, value: u8, ciram: &mut [u8]) {
+        self.mapper.ppu_address(address);
+        match self.mirroring().nametable_address(address) {
+            address if address < ciram.len() => ciram[address] = value,
+            address => self.vram[address - ciram.len()] = value,
+        }
     }
 }
 
-impl std::ops::IndexMut<u16> for Cartridge {
-    fn index_mut(&mut self, address: u16) -> &mut Self::Output {
-        &mut self.prg_rom[self.mapper.address(address) as usize]
+/// Bus conflicts of the discrete boards of mappers 2, 3 and 7. NES 2.0 submapper 1 has none
+/// and 2 has AND-type ones. Submapper 0, unspecified like every iNES header, follows the
+/// common board: UNROM and CNROM have them. AxROM has none, like ANROM, since games written
+/// for it write values that conflict with the ROM.
+fn has_bus_conflicts(mapper: u16, submapper: u8) -> bool {
+    match submapper {
+        1 => false,
+        2 => true,
+        _ => mapper != 7,
     }
 }
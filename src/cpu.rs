@@ -1,28 +1,101 @@
-use crate::cartridge::Cartridge;
+use crate::breakpoints::{self, BreakpointHit, Breakpoints, Registers};
+use crate::bus::{Bus, SystemBus};
 use crate::flags::CPUFlags;
 use crate::opcodes::{AddressingMode, Instruction, OPCODES};
 use crate::util::{page_of, BitOperations};
 
+/// Bits of A kept by the unstable XAA and LAX #imm. It depends on the chip and temperature,
+/// $EE is the most common value.
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+/// What the CPU is doing, returned by `clock` and `step`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpuState {
+    Running,
+    /// A JAM opcode locked up the CPU, only a reset gets it out of it.
+    Jammed {
+        pc: u16,
+        opcode: u8,
+    },
+    /// The BRK at `pc` started while `break_on_brk` is set.
+    /// The CPU keeps running, this is only a notification for debuggers.
+    Break {
+        pc: u16,
+    },
+    /// A breakpoint or watchpoint was hit. Execute breakpoints stop before the instruction
+    /// runs, watchpoints during the access.
+    Breakpoint(BreakpointHit),
+}
+
+/// What the BRK sequence runs for. Interrupts and reset replace the fetched opcode with BRK.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Sequence {
+    Brk,
+    /// NMI or IRQ
+    Interrupt,
+    /// Like an interrupt, with the stack writes turned into reads
+    Reset,
+}
+
+/// How an instruction uses its effective address.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+/// The 6502 core, emulated one cycle at a time. Every `clock` does exactly the bus access
+/// the real chip does on that cycle, dummy reads and writes included.
+/// See https://www.nesdev.org/6502_cpu.txt
 #[derive(Debug)]
-pub struct CPU {
+pub struct CPU<B: Bus = SystemBus> {
     pub a: u8,
     pub x: u8,
     pub y: u8,
     pub pc: u16,
     pub s: u8,
     pub flags: CPUFlags,
-    pub ram: [u8; 0x800],
-    pub cartridge: Cartridge,
+    pub bus: B,
+
+    pub nmi_pending: bool,
+    pub state: CpuState,
+    /// Reports BRK instructions as `CpuState::Break`, to use them as breakpoints
+    pub break_on_brk: bool,
+    pub breakpoints: Breakpoints,
+    /// Cycles run since the CPU was created
+    pub cycles: u64,
 
-    pub instruction_target: u16,
-    pub cycles_remaining: u8,
+    /// Cycle of the current instruction, 0 when the next one fetches an opcode
+    cycle: u8,
+    opcode: u8,
+    /// Effective address, built during the addressing cycles
+    address: u16,
+    /// Zero page pointer of the indirect addressing modes
+    pointer: u8,
+    /// Internal data latch
+    data: u8,
+    /// The index carried into the high byte of the address, which takes a cycle to fix
+    page_crossed: bool,
+    sequence: Sequence,
+    /// Interrupt lines polled at the end of the last cycle
+    interrupt_poll: bool,
+    /// Interrupt lines polled at the end of the cycle before,
+    /// an instruction services what was polled on its second to last cycle
+    previous_interrupt_poll: bool,
+    /// First breakpoint hit during the current cycle
+    breakpoint_hit: Option<BreakpointHit>,
 
     pub(crate) enable_logging: bool,
     pub(crate) logs: String,
 }
 
-impl CPU {
-    pub fn new(cartridge: Cartridge) -> Self {
+impl<B: Bus> CPU<B> {
+    pub fn new(bus: B) -> Self {
         let mut cpu = Self {
             a: 0,
             x: 0,
@@ -30,756 +103,1003 @@ impl CPU {
             pc: 0,
             s: 0,
             flags: CPUFlags::new(),
-            ram: [0; 0x800],
-            cartridge,
-            instruction_target: 0,
-            cycles_remaining: 0,
+            bus,
+            nmi_pending: false,
+            state: CpuState::Running,
+            break_on_brk: false,
+            breakpoints: Breakpoints::default(),
+            cycles: 0,
+            cycle: 0,
+            opcode: 0,
+            address: 0,
+            pointer: 0,
+            data: 0,
+            page_crossed: false,
+            sequence: Sequence::Brk,
+            interrupt_poll: false,
+            previous_interrupt_poll: false,
+            breakpoint_hit: None,
             enable_logging: false,
             logs: String::new(),
         };
-        cpu.reset();
+        cpu.power_on();
         cpu
     }
 
-    pub fn clock(&mut self) {
-        if self.cycles_remaining > 0 {
-            self.cycles_remaining -= 1;
-            return;
+    /// Runs one CPU cycle.
+    pub fn clock(&mut self) -> CpuState {
+        if let CpuState::Jammed { .. } = self.state {
+            // The rest of the console keeps running
+            self.tick();
+            return self.state;
         }
-        self.execute_next_instruction();
-        self.cycles_remaining -= 1;
-    }
-
-    pub fn irq(&mut self) {
-        if self.flags.interrupt_disable {
-            return;
+        // The DMA halts the CPU on a read, the opcode fetch after the write to $4014
+        if self.cycle == 0 && self.bus.dma_active() {
+            self.bus.dma_cycle();
+            self.tick();
+            return self.state;
         }
 
-        self.push_u16(self.pc);
-
-        let mut byte = self.flags.to_byte();
-        // Set B flag to 10, hardware interrupt
-        byte.set_bit(4, false);
-        byte.set_bit(5, true);
-        self.push_u8(byte);
-
-        // Ignore further interrupts
-        self.flags.interrupt_disable = true;
+        let mut brk = None;
+        if self.cycle == 0 {
+            let pc = self.pc;
+            self.start_instruction();
+            if self.break_on_brk && self.sequence == Sequence::Brk && self.opcode == 0x00 {
+                brk = Some(pc);
+            }
+            self.cycle = 1;
+        } else if self.execute_cycle() {
+            self.cycle = 0;
+            if self.breakpoints.watches(breakpoints::Access::Execute)
+                && self.state == CpuState::Running
+            {
+                let opcode = self.peek(self.pc);
+                self.check_breakpoints(breakpoints::Access::Execute, self.pc, opcode);
+            }
+        } else {
+            self.cycle += 1;
+        }
 
-        // Load the IRQ interrupt
-        self.pc = {
-            let lsb = self.read(0xFFFE);
-            let msb = self.read(0xFFFF);
-            u16::from_le_bytes([lsb, msb])
-        };
+        self.tick();
+        if let Some(hit) = self.breakpoint_hit.take() {
+            return CpuState::Breakpoint(hit);
+        }
+        match brk {
+            Some(pc) => CpuState::Break { pc },
+            None => self.state,
+        }
     }
 
-    pub fn nmi(&mut self) {
-        self.push_u16(self.pc);
+    /// Runs until the end of the current instruction, or of the next one
+    /// if the CPU is between two instructions.
+    /// A BRK reported with `break_on_brk` or a watchpoint hit is returned once the whole
+    /// instruction has run, the first one if there are several.
+    pub fn step(&mut self) -> CpuState {
+        let mut result = CpuState::Running;
+        loop {
+            match self.clock() {
+                CpuState::Running => {}
+                state @ (CpuState::Break { .. } | CpuState::Breakpoint(_)) => {
+                    if result == CpuState::Running {
+                        result = state;
+                    }
+                }
+                state @ CpuState::Jammed { .. } => return state,
+            }
+            if self.at_instruction_boundary() {
+                return result;
+            }
+        }
+    }
 
-        let mut byte = self.flags.to_byte();
-        // Set B flag to 10, hardware interrupt
-        byte.set_bit(4, false);
-        byte.set_bit(5, true);
-        self.push_u8(byte);
+    /// True when the next cycle fetches an opcode, and not a DMA cycle.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.cycle == 0 && !self.bus.dma_active()
+    }
 
-        // Ignore further interrupts
-        self.flags.interrupt_disable = true;
+    fn tick(&mut self) {
+        self.cycles += 1;
+        self.bus.tick();
+        // NMI is edge triggered, the edge is latched until the NMI is serviced
+        if self.bus.poll_nmi() {
+            self.nmi_pending = true;
+        }
+        // IRQ is level triggered and masked by the I flag
+        self.previous_interrupt_poll = self.interrupt_poll;
+        self.interrupt_poll = self.nmi_pending || (self.bus.irq() && !self.flags.interrupt_disable);
+    }
 
-        // Load the IRQ interrupt
-        self.pc = {
-            let lsb = self.read(0xFFFA);
-            let msb = self.read(0xFFFB);
-            u16::from_le_bytes([lsb, msb])
-        };
+    /// Turns the console on: P is $34, S ends up at $FD after the reset sequence.
+    pub fn power_on(&mut self) {
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.s = 0;
+        self.flags = CPUFlags::from_byte(0x34);
+        self.cycles = 0;
+        self.reset();
     }
 
+    /// Presses the reset button: A, X and Y are kept, S is decremented by 3 and I is set,
+    /// then PC is loaded from the reset vector. Runs the 7 cycles of the reset sequence.
     pub fn reset(&mut self) {
-        self.s = self.s.wrapping_sub(3);
-        self.flags.interrupt_disable = true;
-        // Load the IRQ interrupt
-        self.pc = {
-            let lsb = self.read(0xFFFC);
-            let msb = self.read(0xFFFD);
-            u16::from_le_bytes([lsb, msb])
-        };
-    }
+        self.state = CpuState::Running;
+        self.cycle = 0;
+        self.nmi_pending = false;
+        self.interrupt_poll = false;
+        self.previous_interrupt_poll = false;
+        self.bus.reset();
 
-    pub fn execute_next_instruction(&mut self) {
-        if self.enable_logging {
-            self.disassemble_and_log_current_instruction();
-        }
+        self.sequence = Sequence::Reset;
+        self.step();
+    }
 
-        let op = self.read(self.pc);
-        self.pc += 1;
-        let (instruction, addressing_mode, cycles) = OPCODES[op as usize];
+    /// First cycle of an instruction, the opcode fetch.
+    fn start_instruction(&mut self) {
+        self.page_crossed = false;
 
-        self.cycles_remaining = cycles;
-        let additional_cycles = self.compute_instruction_target(addressing_mode);
-        let can_take_more_cycles = self.execute_instruction(instruction, addressing_mode);
+        // Interrupts are serviced between instructions
+        if self.sequence != Sequence::Reset && self.previous_interrupt_poll {
+            self.sequence = Sequence::Interrupt;
+        }
 
-        // Some instructions don't consume more cycles when a new page is crossed.
-        if can_take_more_cycles {
-            self.cycles_remaining += additional_cycles;
+        if self.sequence != Sequence::Brk {
+            // The opcode is replaced by BRK and PC isn't incremented
+            self.read(self.pc);
+            self.opcode = 0x00;
+        } else {
+            if self.enable_logging {
+                self.disassemble_and_log_current_instruction();
+            }
+            self.opcode = self.fetch();
         }
     }
 
-    /// Returns 1 if the addressing needs 1 more cycle (pages crossed)
-    pub fn compute_instruction_target(&mut self, addressing_mode: AddressingMode) -> u8 {
-        match addressing_mode {
-            AddressingMode::Implicit => 0,
-            AddressingMode::Accumulator => 0,
-            AddressingMode::Immediate => {
-                self.instruction_target = self.read(self.pc) as u16;
-                self.pc += 1;
-                0
-            }
-            AddressingMode::ZeroPage => {
-                self.instruction_target = self.read(self.pc) as u16;
-                self.pc += 1;
-                0
-            }
-            AddressingMode::ZeroPageIndexedX => {
-                let addr = self.read(self.pc);
-                self.pc += 1;
-                let addr = addr.wrapping_add(self.x);
-                self.instruction_target = addr as u16;
-                0
-            }
-            AddressingMode::ZeroPageIndexedY => {
-                let addr = self.read(self.pc);
-                self.pc += 1;
-                let addr = addr.wrapping_add(self.y);
-                self.instruction_target = addr as u16;
-                0
-            }
-            // This addressing mode is only used for branching instructions.
-            AddressingMode::Relative => {
-                let offset = self.read(self.pc);
-                self.pc += 1;
-                // We must cast it to i8 first because the negative sign bit
-                // must be repeated when we make it 16 bit.
-                let absolute_offset = self.pc.wrapping_add(offset as i8 as u16);
-                self.instruction_target = absolute_offset;
-                if page_of(self.pc) != page_of(absolute_offset) {
-                    2 // Special case
-                } else {
-                    0
-                }
-            }
-            AddressingMode::Absolute => {
-                let addr = {
-                    let lsb = self.read(self.pc);
-                    self.pc += 1;
-                    let msb = self.read(self.pc);
-                    self.pc += 1;
-                    u16::from_le_bytes([lsb, msb])
-                };
-                self.instruction_target = addr;
-                0
-            }
-            AddressingMode::AbsoluteIndexedX => {
-                let addr = {
-                    let lsb = self.read(self.pc);
-                    self.pc += 1;
-                    let msb = self.read(self.pc);
-                    self.pc += 1;
-                    u16::from_le_bytes([lsb, msb])
+    /// Runs the current cycle of the instruction, returns true if it was the last one.
+    fn execute_cycle(&mut self) -> bool {
+        let (instruction, addressing_mode, _) = OPCODES[self.opcode as usize];
+
+        match instruction {
+            Instruction::BRK => self.brk_cycle(),
+            Instruction::JSR => self.jsr_cycle(),
+            Instruction::RTS => self.rts_cycle(),
+            Instruction::RTI => self.rti_cycle(),
+            Instruction::JMP => self.jmp_cycle(addressing_mode),
+            Instruction::PHA | Instruction::PHP => self.push_cycle(instruction),
+            Instruction::PLA | Instruction::PLP => self.pull_cycle(instruction),
+            Instruction::BCC
+            | Instruction::BCS
+            | Instruction::BEQ
+            | Instruction::BMI
+            | Instruction::BNE
+            | Instruction::BPL
+            | Instruction::BVC
+            | Instruction::BVS => self.branch_cycle(instruction),
+            Instruction::JAM => {
+                self.read(self.pc);
+                // Stuck on the opcode
+                self.pc = self.pc.wrapping_sub(1);
+                self.state = CpuState::Jammed {
+                    pc: self.pc,
+                    opcode: self.opcode,
                 };
-                let old_addr = addr;
-                let addr = addr.wrapping_add(self.x as u16);
-                self.instruction_target = addr;
-                if page_of(addr) != page_of(old_addr) {
-                    1
-                } else {
-                    0
-                }
+                true
             }
-            AddressingMode::AbsoluteIndexedY => {
-                let addr = {
-                    let lsb = self.read(self.pc);
-                    self.pc += 1;
-                    let msb = self.read(self.pc);
-                    self.pc += 1;
-                    u16::from_le_bytes([lsb, msb])
-                };
-                let old_addr = addr;
-                let addr = addr.wrapping_add(self.y as u16);
-                self.instruction_target = addr;
-                if page_of(addr) != page_of(old_addr) {
-                    1
+            _ => {
+                let access = access(instruction);
+                let addressing_cycles = self.addressing_cycles(addressing_mode, access);
+                if self.cycle <= addressing_cycles {
+                    self.addressing_cycle(addressing_mode);
+                    false
                 } else {
-                    0
+                    let cycle = self.cycle - addressing_cycles;
+                    self.operation_cycle(instruction, addressing_mode, access, cycle)
                 }
             }
-            AddressingMode::Indirect => {
-                let (addr, lsb) = {
-                    let lsb = self.read(self.pc);
-                    self.pc += 1;
-                    let msb = self.read(self.pc);
-                    self.pc += 1;
-                    (u16::from_le_bytes([lsb, msb]), lsb)
-                };
-                let addr = {
-                    // Hardware bug
-                    if lsb == 0xFF {
-                        let lsb = self.read(addr);
-                        let msb = self.read(addr & 0xFF00);
-                        u16::from_le_bytes([lsb, msb])
-                    } else {
-                        let lsb = self.read(addr);
-                        let msb = self.read(addr + 1);
-                        u16::from_le_bytes([lsb, msb])
-                    }
-                };
-                self.instruction_target = addr;
-                0
-            }
-            AddressingMode::IndexedIndirect => {
-                let addr = self.read(self.pc);
-                self.pc += 1;
-                let addr = {
-                    let lsb = self.read(addr.wrapping_add(self.x) as u16);
-                    let msb = self.read(addr.wrapping_add(self.x).wrapping_add(1) as u16);
-                    u16::from_le_bytes([lsb, msb])
-                };
-                self.instruction_target = addr;
-                0
-            }
-            AddressingMode::IndirectIndexed => {
-                let addr = self.read(self.pc);
-                self.pc += 1;
-                let old_addr = addr;
-                let addr = {
-                    let lsb = self.read(addr as u16);
-                    let msb = self.read(addr.wrapping_add(1) as u16);
-                    u16::from_le_bytes([lsb, msb])
-                };
-                let addr = addr.wrapping_add(self.y as u16);
-                self.instruction_target = addr;
-                if page_of(addr) != page_of(old_addr as u16) {
-                    1
-                } else {
-                    0
+        }
+    }
+
+    /// Number of cycles after the opcode fetch spent computing the effective address.
+    fn addressing_cycles(&self, addressing_mode: AddressingMode, access: Access) -> u8 {
+        // Only reads skip fixing the high byte when no page is crossed
+        let fix_up = (self.page_crossed || access != Access::Read) as u8;
+        match addressing_mode {
+            AddressingMode::ZeroPage => 1,
+            AddressingMode::ZeroPageIndexedX => 2,
+            AddressingMode::ZeroPageIndexedY => 2,
+            AddressingMode::Absolute => 2,
+            AddressingMode::AbsoluteIndexedX => 2 + fix_up,
+            AddressingMode::AbsoluteIndexedY => 2 + fix_up,
+            AddressingMode::IndexedIndirect => 4,
+            AddressingMode::IndirectIndexed => 3 + fix_up,
+            _ => 0,
+        }
+    }
+
+    fn addressing_cycle(&mut self, addressing_mode: AddressingMode) {
+        match (addressing_mode, self.cycle) {
+            (AddressingMode::ZeroPage, _)
+            | (AddressingMode::ZeroPageIndexedX, 1)
+            | (AddressingMode::ZeroPageIndexedY, 1)
+            | (AddressingMode::Absolute, 1)
+            | (AddressingMode::AbsoluteIndexedX, 1)
+            | (AddressingMode::AbsoluteIndexedY, 1) => {
+                self.address = self.fetch() as u16;
+            }
+            (AddressingMode::ZeroPageIndexedX, _) => {
+                // Reads the unindexed address while adding
+                self.read(self.address);
+                self.address = (self.address as u8).wrapping_add(self.x) as u16;
+            }
+            (AddressingMode::ZeroPageIndexedY, _) => {
+                self.read(self.address);
+                self.address = (self.address as u8).wrapping_add(self.y) as u16;
+            }
+            (AddressingMode::Absolute, _) => {
+                let high = self.fetch();
+                self.address.set_bits(8..=15, high as u16);
+            }
+            (AddressingMode::AbsoluteIndexedX, 2) => {
+                let high = self.fetch();
+                self.index_address(high, self.x);
+            }
+            (AddressingMode::AbsoluteIndexedY, 2) => {
+                let high = self.fetch();
+                self.index_address(high, self.y);
+            }
+            (AddressingMode::IndexedIndirect, 1) | (AddressingMode::IndirectIndexed, 1) => {
+                self.pointer = self.fetch();
+            }
+            (AddressingMode::IndexedIndirect, 2) => {
+                self.read(self.pointer as u16);
+                self.pointer = self.pointer.wrapping_add(self.x);
+            }
+            (AddressingMode::IndexedIndirect, 3) | (AddressingMode::IndirectIndexed, 2) => {
+                self.address = self.read(self.pointer as u16) as u16;
+            }
+            (AddressingMode::IndexedIndirect, _) => {
+                let high = self.read(self.pointer.wrapping_add(1) as u16);
+                self.address.set_bits(8..=15, high as u16);
+            }
+            (AddressingMode::IndirectIndexed, 3) => {
+                let high = self.read(self.pointer.wrapping_add(1) as u16);
+                self.index_address(high, self.y);
+            }
+            (AddressingMode::AbsoluteIndexedX, _)
+            | (AddressingMode::AbsoluteIndexedY, _)
+            | (AddressingMode::IndirectIndexed, _) => {
+                // Reads the address before the carry is added to its high byte
+                self.read(self.address);
+                if self.page_crossed {
+                    self.address = self.address.wrapping_add(0x100);
                 }
             }
+            _ => unreachable!("{:?} has no addressing cycles", addressing_mode),
         }
     }
 
-    pub fn execute_instruction(
+    /// Adds an index to the low byte of the address, the carry is added to the high byte
+    /// on the next cycle.
+    fn index_address(&mut self, high: u8, index: u8) {
+        let (low, carry) = (self.address as u8).overflowing_add(index);
+        self.address = u16::from_le_bytes([low, high]);
+        self.page_crossed = carry;
+    }
+
+    /// Cycles once the effective address is known, `cycle` starts at 1.
+    /// Returns true on the last cycle of the instruction.
+    fn operation_cycle(
         &mut self,
         instruction: Instruction,
         addressing_mode: AddressingMode,
+        access: Access,
+        cycle: u8,
     ) -> bool {
-        match instruction {
-            Instruction::ADC => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
-                } else {
-                    self.read(self.instruction_target)
-                };
-                let carry = self.flags.carry as u8;
-                let will_carry = self
-                    .a
-                    .checked_add(value)
-                    .and_then(|x| x.checked_add(carry))
-                    .is_none();
-
-                let old_a = self.a;
-                self.a = self.a.wrapping_add(value).wrapping_add(carry);
-
-                self.flags.carry = will_carry;
-                self.flags.overflow =
-                    old_a.get_bit(7) == value.get_bit(7) && old_a.get_bit(7) != self.a.get_bit(7);
-                self.flags.zero = self.a == 0;
-                self.flags.negative = self.a.get_bit(7);
-                true
+        match addressing_mode {
+            AddressingMode::Implicit => {
+                self.read(self.pc);
+                self.execute_implied(instruction);
+                return true;
             }
-            Instruction::AND => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
-                } else {
-                    self.read(self.instruction_target)
-                };
-                self.a &= value;
+            AddressingMode::Accumulator => {
+                self.read(self.pc);
+                self.a = self.read_modify_write(instruction, self.a);
+                return true;
+            }
+            AddressingMode::Immediate => {
+                let value = self.fetch();
+                self.execute_read(instruction, addressing_mode, value);
+                return true;
+            }
+            _ => {}
+        }
 
-                self.flags.zero = self.a == 0;
-                self.flags.negative = self.a.get_bit(7);
+        match (access, cycle) {
+            (Access::Read, _) => {
+                let value = self.read(self.address);
+                self.execute_read(instruction, addressing_mode, value);
                 true
             }
-            Instruction::ASL => {
-                if let AddressingMode::Accumulator = addressing_mode {
-                    self.flags.carry = self.a.get_bit(7);
-                    self.a <<= 1;
-                    self.flags.zero = self.a == 0;
-                    self.flags.negative = self.a.get_bit(7);
-                } else {
-                    let mut value = self.read(self.instruction_target);
-                    self.flags.carry = value.get_bit(7);
-                    value <<= 1;
-                    self.write(self.instruction_target, value);
-                    self.flags.zero = value == 0;
-                    self.flags.negative = value.get_bit(7);
-                }
+            (Access::Write, _) => {
+                self.execute_write(instruction, addressing_mode);
+                true
+            }
+            (Access::ReadModifyWrite, 1) => {
+                self.data = self.read(self.address);
                 false
             }
-            Instruction::BCC => {
-                if !self.flags.carry {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
-                }
-                true
+            (Access::ReadModifyWrite, 2) => {
+                // The unmodified value is written back while the ALU works
+                self.write(self.address, self.data);
+                self.data = self.read_modify_write(instruction, self.data);
+                false
             }
-            Instruction::BCS => {
-                if self.flags.carry {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
-                }
+            (Access::ReadModifyWrite, _) => {
+                self.write(self.address, self.data);
                 true
             }
-            Instruction::BEQ => {
-                if self.flags.zero {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
+        }
+    }
+
+    fn branch_cycle(&mut self, instruction: Instruction) -> bool {
+        match self.cycle {
+            1 => {
+                self.data = self.fetch();
+                !self.is_branch_taken(instruction)
+            }
+            2 => {
+                self.read(self.pc);
+                // We must cast it to i8 first because the negative sign bit
+                // must be repeated when we make it 16 bit.
+                self.address = self.pc.wrapping_add(self.data as i8 as u16);
+                // The high byte is fixed on the next cycle
+                self.pc.set_bits(0..=7, self.address & 0xFF);
+                if self.pc == self.address {
+                    // Without a page crossing, interrupts aren't polled on the last cycle.
+                    // An interrupt arriving during the first cycles is serviced after
+                    // the next instruction.
+                    self.interrupt_poll = self.previous_interrupt_poll;
+                    return true;
                 }
+                false
+            }
+            _ => {
+                self.read(self.pc);
+                self.pc = self.address;
                 true
             }
-            Instruction::BIT => {
-                let value = self.read(self.instruction_target);
-                self.flags.zero = (self.a & value) == 0;
-                self.flags.negative = value.get_bit(7);
-                self.flags.overflow = value.get_bit(6);
+        }
+    }
+
+    fn is_branch_taken(&self, instruction: Instruction) -> bool {
+        match instruction {
+            Instruction::BCC => !self.flags.carry,
+            Instruction::BCS => self.flags.carry,
+            Instruction::BEQ => self.flags.zero,
+            Instruction::BMI => self.flags.negative,
+            Instruction::BNE => !self.flags.zero,
+            Instruction::BPL => !self.flags.negative,
+            Instruction::BVC => !self.flags.overflow,
+            Instruction::BVS => self.flags.overflow,
+            _ => unreachable!("{:?} isn't a branch", instruction),
+        }
+    }
+
+    fn jmp_cycle(&mut self, addressing_mode: AddressingMode) -> bool {
+        match (addressing_mode, self.cycle) {
+            (_, 1) => {
+                self.address = self.fetch() as u16;
                 false
             }
-            Instruction::BMI => {
-                if self.flags.negative {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
-                }
+            (AddressingMode::Absolute, _) => {
+                let high = self.read(self.pc);
+                self.pc = u16::from_le_bytes([self.address as u8, high]);
                 true
             }
-            Instruction::BNE => {
-                if !self.flags.zero {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
-                }
-                true
+            (_, 2) => {
+                let high = self.fetch();
+                self.address.set_bits(8..=15, high as u16);
+                false
             }
-            Instruction::BPL => {
-                if !self.flags.negative {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
-                }
+            (_, 3) => {
+                self.data = self.read(self.address);
+                false
+            }
+            _ => {
+                // Hardware bug, the carry of the low byte isn't added to the high byte
+                let high_address = (self.address & 0xFF00) | (self.address.wrapping_add(1) & 0xFF);
+                let high = self.read(high_address);
+                self.pc = u16::from_le_bytes([self.data, high]);
                 true
             }
-            Instruction::BRK => {
-                if self.flags.interrupt_disable {
-                    return false;
-                }
-
-                self.push_u16(self.pc);
-
-                let mut byte = self.flags.to_byte();
-                // Set B flag to 11, software interrupt
-                byte.set_bit(4, true);
-                byte.set_bit(5, true);
-                self.push_u8(byte);
-
-                // Ignore further interrupts
-                self.flags.interrupt_disable = true;
+        }
+    }
 
-                // Load the IRQ interrupt
-                self.pc = {
-                    let lsb = self.read(0xFFFE);
-                    let msb = self.read(0xFFFF);
-                    u16::from_le_bytes([lsb, msb])
-                };
+    fn jsr_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.address = self.fetch() as u16;
                 false
             }
-            Instruction::BVC => {
-                if !self.flags.overflow {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
-                }
+            2 => {
+                self.read(self.stack_address());
+                false
+            }
+            // PC points to the high byte of the target, the return address minus one
+            3 => {
+                self.push(page_of(self.pc));
+                false
+            }
+            4 => {
+                self.push(self.pc as u8);
+                false
+            }
+            _ => {
+                let high = self.read(self.pc);
+                self.pc = u16::from_le_bytes([self.address as u8, high]);
                 true
             }
-            Instruction::BVS => {
-                if self.flags.overflow {
-                    self.pc = self.instruction_target;
-                    self.cycles_remaining += 1;
-                }
+        }
+    }
+
+    fn rts_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.read(self.pc);
+                false
+            }
+            2 => {
+                self.read(self.stack_address());
+                self.s = self.s.wrapping_add(1);
+                false
+            }
+            3 => {
+                self.address = self.read(self.stack_address()) as u16;
+                self.s = self.s.wrapping_add(1);
+                false
+            }
+            4 => {
+                let high = self.read(self.stack_address());
+                self.pc = u16::from_le_bytes([self.address as u8, high]);
+                false
+            }
+            _ => {
+                // Skips the last byte of the JSR
+                self.fetch();
                 true
             }
-            Instruction::CLC => {
-                self.flags.carry = false;
+        }
+    }
+
+    fn rti_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                self.read(self.pc);
                 false
             }
-            Instruction::CLD => {
-                self.flags.decimal_mode = false;
+            2 => {
+                self.read(self.stack_address());
+                self.s = self.s.wrapping_add(1);
                 false
             }
-            Instruction::CLI => {
-                self.flags.interrupt_disable = false;
+            3 => {
+                self.flags = CPUFlags::from_byte(self.read(self.stack_address()));
+                self.s = self.s.wrapping_add(1);
                 false
             }
-            Instruction::CLV => {
-                self.flags.overflow = false;
+            4 => {
+                self.address = self.read(self.stack_address()) as u16;
+                self.s = self.s.wrapping_add(1);
                 false
             }
-            Instruction::CMP => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
+            _ => {
+                let high = self.read(self.stack_address());
+                self.pc = u16::from_le_bytes([self.address as u8, high]);
+                true
+            }
+        }
+    }
+
+    fn push_cycle(&mut self, instruction: Instruction) -> bool {
+        if self.cycle == 1 {
+            self.read(self.pc);
+            return false;
+        }
+
+        let value = if let Instruction::PHA = instruction {
+            self.a
+        } else {
+            let mut byte = self.flags.to_byte();
+            // Set B flag to 11
+            byte.set_bit(4, true);
+            byte.set_bit(5, true);
+            byte
+        };
+        self.push(value);
+        true
+    }
+
+    fn pull_cycle(&mut self, instruction: Instruction) -> bool {
+        match self.cycle {
+            1 => {
+                self.read(self.pc);
+                false
+            }
+            2 => {
+                self.read(self.stack_address());
+                self.s = self.s.wrapping_add(1);
+                false
+            }
+            _ => {
+                let value = self.read(self.stack_address());
+                if let Instruction::PLA = instruction {
+                    self.a = value;
+                    self.flags.zero = value == 0;
+                    self.flags.negative = value.get_bit(7);
                 } else {
-                    self.read(self.instruction_target)
-                };
-                self.flags.carry = self.a >= value;
-                self.flags.zero = self.a == value;
-                self.flags.negative = self.a.wrapping_sub(value).get_bit(7);
+                    self.flags = CPUFlags::from_byte(value);
+                }
                 true
             }
-            Instruction::CPX => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
+        }
+    }
+
+    /// BRK, and the NMI, IRQ and reset sequences which only differ by their B flag and vector.
+    fn brk_cycle(&mut self) -> bool {
+        match self.cycle {
+            1 => {
+                if self.sequence == Sequence::Brk {
+                    // BRK skips the padding byte following it
+                    self.fetch();
                 } else {
-                    self.read(self.instruction_target)
-                };
-                self.flags.carry = self.x >= value;
-                self.flags.zero = self.x == value;
-                self.flags.negative = self.x.wrapping_sub(value).get_bit(7);
+                    self.read(self.pc);
+                }
                 false
             }
-            Instruction::CPY => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
+            2 => {
+                self.push(page_of(self.pc));
+                false
+            }
+            3 => {
+                self.push(self.pc as u8);
+                false
+            }
+            4 => {
+                let mut byte = self.flags.to_byte();
+                // B flag is 11 for BRK, 10 for hardware interrupts
+                byte.set_bit(4, self.sequence == Sequence::Brk);
+                byte.set_bit(5, true);
+                self.push(byte);
+
+                // Ignore further interrupts
+                self.flags.interrupt_disable = true;
+
+                // An NMI arriving until now hijacks the sequence, BRK and IRQ jump to its vector
+                self.address = if self.sequence == Sequence::Reset {
+                    RESET_VECTOR
+                } else if self.nmi_pending {
+                    self.nmi_pending = false;
+                    NMI_VECTOR
                 } else {
-                    self.read(self.instruction_target)
+                    IRQ_VECTOR
                 };
-                self.flags.carry = self.y >= value;
-                self.flags.zero = self.y == value;
-                self.flags.negative = self.y.wrapping_sub(value).get_bit(7);
                 false
             }
-            Instruction::DEC => {
-                let value = self.read(self.instruction_target);
-                let result = value.wrapping_sub(1);
-                self.write(self.instruction_target, result);
-                self.flags.zero = result == 0;
-                self.flags.negative = result.get_bit(7);
+            5 => {
+                self.data = self.read(self.address);
                 false
             }
+            _ => {
+                let high = self.read(self.address + 1);
+                self.pc = u16::from_le_bytes([self.data, high]);
+                self.sequence = Sequence::Brk;
+                // The sequence doesn't poll interrupts,
+                // the first instruction of the handler always runs
+                self.interrupt_poll = false;
+                true
+            }
+        }
+    }
+
+    fn execute_implied(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::CLC => self.flags.carry = false,
+            Instruction::CLD => self.flags.decimal_mode = false,
+            Instruction::CLI => self.flags.interrupt_disable = false,
+            Instruction::CLV => self.flags.overflow = false,
+            Instruction::SEC => self.flags.carry = true,
+            Instruction::SED => self.flags.decimal_mode = true,
+            Instruction::SEI => self.flags.interrupt_disable = true,
             Instruction::DEX => {
                 self.x = self.x.wrapping_sub(1);
                 self.flags.zero = self.x == 0;
                 self.flags.negative = self.x.get_bit(7);
-                false
             }
             Instruction::DEY => {
                 self.y = self.y.wrapping_sub(1);
                 self.flags.zero = self.y == 0;
                 self.flags.negative = self.y.get_bit(7);
-                false
-            }
-            Instruction::EOR => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
-                } else {
-                    self.read(self.instruction_target)
-                };
-                self.a ^= value;
-                self.flags.zero = self.a == 0;
-                self.flags.negative = self.a.get_bit(7);
-                true
-            }
-            Instruction::INC => {
-                let value = self.read(self.instruction_target);
-                let result = value.wrapping_add(1);
-                self.write(self.instruction_target, result);
-                self.flags.zero = result == 0;
-                self.flags.negative = result.get_bit(7);
-                false
             }
             Instruction::INX => {
                 self.x = self.x.wrapping_add(1);
                 self.flags.zero = self.x == 0;
                 self.flags.negative = self.x.get_bit(7);
-                false
             }
             Instruction::INY => {
                 self.y = self.y.wrapping_add(1);
                 self.flags.zero = self.y == 0;
                 self.flags.negative = self.y.get_bit(7);
-                false
             }
-            Instruction::JMP => {
-                self.pc = self.instruction_target;
-                false
+            Instruction::TAX => {
+                self.x = self.a;
+                self.flags.zero = self.x == 0;
+                self.flags.negative = self.x.get_bit(7);
             }
-            Instruction::JSR => {
-                let pc_minus_one = self.pc.wrapping_sub(1);
-                self.push_u16(pc_minus_one);
-                self.pc = self.instruction_target;
-                false
+            Instruction::TAY => {
+                self.y = self.a;
+                self.flags.zero = self.y == 0;
+                self.flags.negative = self.y.get_bit(7);
+            }
+            Instruction::TSX => {
+                self.x = self.s;
+                self.flags.zero = self.x == 0;
+                self.flags.negative = self.x.get_bit(7);
+            }
+            Instruction::TXA => {
+                self.a = self.x;
+                self.flags.zero = self.a == 0;
+                self.flags.negative = self.a.get_bit(7);
+            }
+            Instruction::TXS => self.s = self.x,
+            Instruction::TYA => {
+                self.a = self.y;
+                self.flags.zero = self.a == 0;
+                self.flags.negative = self.a.get_bit(7);
+            }
+            Instruction::NOP => {}
+            _ => unreachable!("{:?} isn't implied", instruction),
+        }
+    }
+
+    /// Instructions using the byte read at the effective address, or the immediate value.
+    fn execute_read(
+        &mut self,
+        instruction: Instruction,
+        addressing_mode: AddressingMode,
+        value: u8,
+    ) {
+        match instruction {
+            Instruction::ADC => self.add_with_carry(value),
+            Instruction::AND => {
+                self.a &= value;
+                self.flags.zero = self.a == 0;
+                self.flags.negative = self.a.get_bit(7);
+            }
+            Instruction::BIT => {
+                self.flags.zero = (self.a & value) == 0;
+                self.flags.negative = value.get_bit(7);
+                self.flags.overflow = value.get_bit(6);
+            }
+            Instruction::CMP => self.compare(self.a, value),
+            Instruction::CPX => self.compare(self.x, value),
+            Instruction::CPY => self.compare(self.y, value),
+            Instruction::EOR => {
+                self.a ^= value;
+                self.flags.zero = self.a == 0;
+                self.flags.negative = self.a.get_bit(7);
             }
             Instruction::LDA => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
-                } else {
-                    self.read(self.instruction_target)
-                };
                 self.a = value;
                 self.flags.zero = value == 0;
                 self.flags.negative = value.get_bit(7);
-                true
             }
             Instruction::LDX => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
-                } else {
-                    self.read(self.instruction_target)
-                };
                 self.x = value;
                 self.flags.zero = value == 0;
                 self.flags.negative = value.get_bit(7);
-                true
             }
             Instruction::LDY => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
-                } else {
-                    self.read(self.instruction_target)
-                };
                 self.y = value;
                 self.flags.zero = value == 0;
                 self.flags.negative = value.get_bit(7);
-                true
-            }
-            Instruction::LSR => {
-                if let AddressingMode::Accumulator = addressing_mode {
-                    self.flags.carry = self.a.get_bit(0);
-                    self.a >>= 1;
-                    self.flags.zero = self.a == 0;
-                    self.flags.negative = self.a.get_bit(7);
-                } else {
-                    let value = self.read(self.instruction_target);
-                    self.flags.carry = value.get_bit(0);
-                    let result = value >> 1;
-                    self.write(self.instruction_target, result);
-                    self.flags.zero = result == 0;
-                    self.flags.negative = result.get_bit(7);
-                }
-                false
             }
-            Instruction::NOP => false,
+            // The unofficial NOPs still read their operand
+            Instruction::NOP => {}
             Instruction::ORA => {
-                let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
-                } else {
-                    self.read(self.instruction_target)
-                };
                 self.a |= value;
                 self.flags.zero = self.a == 0;
                 self.flags.negative = self.a.get_bit(7);
-                true
-            }
-            Instruction::PHA => {
-                self.push_u8(self.a);
-                false
             }
-            Instruction::PHP => {
-                let mut byte = self.flags.to_byte();
-                // Set B flag to 11
-                byte.set_bit(4, true);
-                byte.set_bit(5, true);
-                self.push_u8(byte);
-                false
-            }
-            Instruction::PLA => {
-                self.a = self.pop_u8();
+            // Same as ADC but we invert the value
+            Instruction::SBC => self.add_with_carry(!value),
+            // Unofficial instructions
+            // See https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+            Instruction::ALR => {
+                self.a &= value;
+                self.flags.carry = self.a.get_bit(0);
+                self.a >>= 1;
                 self.flags.zero = self.a == 0;
                 self.flags.negative = self.a.get_bit(7);
-                false
             }
-            Instruction::PLP => {
-                self.flags = CPUFlags::from_byte(self.pop_u8());
-                false
-            }
-            Instruction::ROL => {
-                if let AddressingMode::Accumulator = addressing_mode {
-                    let new_carry = self.a.get_bit(7);
-                    self.a <<= 1;
-                    self.a.set_bit(0, self.flags.carry);
-                    self.flags.carry = new_carry;
-                    self.flags.zero = self.a == 0;
-                    self.flags.negative = self.a.get_bit(7);
-                } else {
-                    let mut value = self.read(self.instruction_target);
-                    let new_carry = value.get_bit(7);
-                    value <<= 1;
-                    value.set_bit(0, self.flags.carry);
-                    self.write(self.instruction_target, value);
-                    self.flags.carry = new_carry;
-                    self.flags.zero = value == 0;
-                    self.flags.negative = value.get_bit(7);
-                }
-                false
-            }
-            Instruction::ROR => {
-                if let AddressingMode::Accumulator = addressing_mode {
-                    let new_carry = self.a.get_bit(0);
-                    self.a >>= 1;
-                    self.a.set_bit(7, self.flags.carry);
-                    self.flags.carry = new_carry;
-                    self.flags.zero = self.a == 0;
-                    self.flags.negative = self.a.get_bit(7);
-                } else {
-                    let mut value = self.read(self.instruction_target);
-                    let new_carry = value.get_bit(0);
-                    value >>= 1;
-                    value.set_bit(7, self.flags.carry);
-                    self.write(self.instruction_target, value);
-                    self.flags.carry = new_carry;
-                    self.flags.zero = value == 0;
-                    self.flags.negative = value.get_bit(7);
-                }
-                false
+            Instruction::ANC => {
+                self.a &= value;
+                self.flags.zero = self.a == 0;
+                self.flags.negative = self.a.get_bit(7);
+                self.flags.carry = self.flags.negative;
             }
-            Instruction::RTI => {
-                self.flags = CPUFlags::from_byte(self.pop_u8());
-                self.pc = self.pop_u16();
-                false
+            Instruction::ARR => {
+                self.a &= value;
+                self.a >>= 1;
+                self.a.set_bit(7, self.flags.carry);
+                self.flags.zero = self.a == 0;
+                self.flags.negative = self.a.get_bit(7);
+                self.flags.carry = self.a.get_bit(6);
+                self.flags.overflow = self.a.get_bit(6) != self.a.get_bit(5);
+            }
+            Instruction::AXS => {
+                let a_and_x = self.a & self.x;
+                // Like CMP, without borrow and not affected by the decimal flag
+                self.x = a_and_x.wrapping_sub(value);
+                self.flags.carry = a_and_x >= value;
+                self.flags.zero = self.x == 0;
+                self.flags.negative = self.x.get_bit(7);
             }
-            Instruction::RTS => {
-                self.pc = self.pop_u16() + 1;
-                false
+            Instruction::LAS => {
+                let value = value & self.s;
+                self.a = value;
+                self.x = value;
+                self.s = value;
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
             }
-            Instruction::SBC => {
+            Instruction::LAX => {
                 let value = if let AddressingMode::Immediate = addressing_mode {
-                    self.instruction_target as u8
+                    // Unstable, the bits of A that are kept depend on the chip
+                    (self.a | UNSTABLE_MAGIC) & value
                 } else {
-                    self.read(self.instruction_target)
+                    value
                 };
-                // Same as ADC but we invert the value
-                let value = !value;
-
-                let carry = self.flags.carry as u8;
-                let will_carry = self
-                    .a
-                    .checked_add(value)
-                    .and_then(|x| x.checked_add(carry))
-                    .is_none();
-
-                let old_a = self.a;
-                self.a = self.a.wrapping_add(value).wrapping_add(carry);
-
-                self.flags.carry = will_carry;
-                self.flags.overflow =
-                    old_a.get_bit(7) == value.get_bit(7) && old_a.get_bit(7) != self.a.get_bit(7);
+                self.a = value;
+                self.x = value;
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
+            }
+            Instruction::XAA => {
+                // Unstable, the bits of A that are kept depend on the chip
+                self.a = (self.a | UNSTABLE_MAGIC) & self.x & value;
                 self.flags.zero = self.a == 0;
                 self.flags.negative = self.a.get_bit(7);
-                true
             }
-            Instruction::SEC => {
-                self.flags.carry = true;
-                false
-            }
-            Instruction::SED => {
-                self.flags.decimal_mode = true;
-                false
+            _ => unreachable!("{:?} doesn't read memory", instruction),
+        }
+    }
+
+    fn execute_write(&mut self, instruction: Instruction, addressing_mode: AddressingMode) {
+        let index = match addressing_mode {
+            AddressingMode::AbsoluteIndexedX => self.x,
+            AddressingMode::AbsoluteIndexedY | AddressingMode::IndirectIndexed => self.y,
+            _ => 0,
+        };
+
+        match instruction {
+            Instruction::STA => self.write(self.address, self.a),
+            Instruction::STX => self.write(self.address, self.x),
+            Instruction::STY => self.write(self.address, self.y),
+            Instruction::SAX => self.write(self.address, self.a & self.x),
+            Instruction::SHA => self.store_and_high_byte(self.a & self.x, index),
+            Instruction::SHX => self.store_and_high_byte(self.x, index),
+            Instruction::SHY => self.store_and_high_byte(self.y, index),
+            Instruction::TAS => {
+                self.s = self.a & self.x;
+                self.store_and_high_byte(self.s, index);
+            }
+            _ => unreachable!("{:?} doesn't write memory", instruction),
+        }
+    }
+
+    /// Instructions modifying memory or the accumulator, returns the new value.
+    fn read_modify_write(&mut self, instruction: Instruction, value: u8) -> u8 {
+        let mut value = value;
+        match instruction {
+            Instruction::ASL => {
+                self.flags.carry = value.get_bit(7);
+                value <<= 1;
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
             }
-            Instruction::SEI => {
-                self.flags.interrupt_disable = true;
-                false
+            Instruction::DEC => {
+                value = value.wrapping_sub(1);
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
             }
-            Instruction::STA => {
-                self.write(self.instruction_target, self.a);
-                false
+            Instruction::INC => {
+                value = value.wrapping_add(1);
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
             }
-            Instruction::STX => {
-                self.write(self.instruction_target, self.x);
-                false
+            Instruction::LSR => {
+                self.flags.carry = value.get_bit(0);
+                value >>= 1;
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
             }
-            Instruction::STY => {
-                self.write(self.instruction_target, self.y);
-                false
+            Instruction::ROL => {
+                let new_carry = value.get_bit(7);
+                value <<= 1;
+                value.set_bit(0, self.flags.carry);
+                self.flags.carry = new_carry;
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
             }
-            Instruction::TAX => {
-                self.x = self.a;
-                self.flags.zero = self.x == 0;
-                self.flags.negative = self.x.get_bit(7);
-                false
+            Instruction::ROR => {
+                let new_carry = value.get_bit(0);
+                value >>= 1;
+                value.set_bit(7, self.flags.carry);
+                self.flags.carry = new_carry;
+                self.flags.zero = value == 0;
+                self.flags.negative = value.get_bit(7);
             }
-            Instruction::TAY => {
-                self.y = self.a;
-                self.flags.zero = self.y == 0;
-                self.flags.negative = self.y.get_bit(7);
-                false
+            // Unofficial instructions, a shift or an increment followed by an ALU operation
+            Instruction::DCP => {
+                value = value.wrapping_sub(1);
+                self.compare(self.a, value);
             }
-            Instruction::TSX => {
-                self.x = self.s;
-                self.flags.zero = self.x == 0;
-                self.flags.negative = self.x.get_bit(7);
-                false
+            Instruction::ISC => {
+                value = value.wrapping_add(1);
+                self.add_with_carry(!value);
             }
-            Instruction::TXA => {
-                self.a = self.x;
+            Instruction::RLA => {
+                value = self.read_modify_write(Instruction::ROL, value);
+                self.a &= value;
                 self.flags.zero = self.a == 0;
                 self.flags.negative = self.a.get_bit(7);
-                false
             }
-            Instruction::TXS => {
-                self.s = self.x;
-                false
+            Instruction::RRA => {
+                value = self.read_modify_write(Instruction::ROR, value);
+                self.add_with_carry(value);
             }
-            Instruction::TYA => {
-                self.a = self.y;
+            Instruction::SLO => {
+                value = self.read_modify_write(Instruction::ASL, value);
+                self.a |= value;
                 self.flags.zero = self.a == 0;
                 self.flags.negative = self.a.get_bit(7);
-                false
             }
+            Instruction::SRE => {
+                value = self.read_modify_write(Instruction::LSR, value);
+                self.a ^= value;
+                self.flags.zero = self.a == 0;
+                self.flags.negative = self.a.get_bit(7);
+            }
+            _ => unreachable!("{:?} doesn't modify memory", instruction),
         }
+        value
+    }
+
+    /// ADC, SBC adds the complement of its operand.
+    fn add_with_carry(&mut self, value: u8) {
+        let carry = self.flags.carry as u8;
+        let will_carry = self
+            .a
+            .checked_add(value)
+            .and_then(|x| x.checked_add(carry))
+            .is_none();
+
+        let old_a = self.a;
+        self.a = self.a.wrapping_add(value).wrapping_add(carry);
+
+        self.flags.carry = will_carry;
+        self.flags.overflow =
+            old_a.get_bit(7) == value.get_bit(7) && old_a.get_bit(7) != self.a.get_bit(7);
+        self.flags.zero = self.a == 0;
+        self.flags.negative = self.a.get_bit(7);
+    }
+
+    fn compare(&mut self, register: u8, value: u8) {
+        self.flags.carry = register >= value;
+        self.flags.zero = register == value;
+        self.flags.negative = register.wrapping_sub(value).get_bit(7);
+    }
+
+    /// SHA, SHX, SHY and TAS store `value & (H + 1)`, H being the high byte of the base address.
+    /// When the index crosses a page, the stored value also replaces the high byte of the target.
+    fn store_and_high_byte(&mut self, value: u8, index: u8) {
+        let base = self.address.wrapping_sub(index as u16);
+        let value = value & page_of(base).wrapping_add(1);
+        let address = if page_of(base) != page_of(self.address) {
+            u16::from_le_bytes([self.address as u8, value])
+        } else {
+            self.address
+        };
+        self.write(address, value);
     }
 
     #[inline]
     pub fn read(&mut self, address: u16) -> u8 {
-        *self.get_reference_to(address)
+        let value = self.bus.read(address);
+        if self.breakpoints.watches(breakpoints::Access::Read) {
+            self.check_breakpoints(breakpoints::Access::Read, address, value);
+        }
+        value
     }
 
     #[inline]
     pub fn write(&mut self, address: u16, value: u8) {
-        *self.get_reference_to(address) = value;
+        self.bus.write(address, value);
+        if self.breakpoints.watches(breakpoints::Access::Write) {
+            self.check_breakpoints(breakpoints::Access::Write, address, value);
+        }
     }
 
-    pub fn get_reference_to(&mut self, address: u16) -> &mut u8 {
-        match address {
-            0x0000..=0x1FFF => &mut self.ram[address as usize & 0x07FF],
-            0x2000..=0x3FFF => unimplemented!("PPU registers"),
-            0x4000..=0x4017 => unimplemented!("APU and I/O registers"),
-            0x4018..=0x401F => {
-                unimplemented!("APU and I/O functionality that is normally disabled")
-            }
-            0x4020..=0xFFFF => &mut self.cartridge[address],
+    /// The registers as seen by breakpoint conditions.
+    pub fn registers(&self, value: u8) -> Registers {
+        Registers {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            s: self.s,
+            pc: self.pc,
+            p: self.flags.to_byte(),
+            value,
+        }
+    }
+
+    #[cold]
+    fn check_breakpoints(&mut self, access: breakpoints::Access, address: u16, value: u8) {
+        let registers = self.registers(value);
+        if let Some(hit) = self.breakpoints.check(access, address, &registers) {
+            self.breakpoint_hit.get_or_insert(hit);
         }
     }
 
-    pub fn push_u8(&mut self, value: u8) {
-        self.write(0x100 + self.s as u16, value);
-        self.s -= 1;
+    #[inline]
+    pub fn peek(&self, address: u16) -> u8 {
+        self.bus.peek(address)
+    }
+
+    /// Reads the byte at PC and moves past it.
+    fn fetch(&mut self) -> u8 {
+        let value = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        value
     }
 
-    pub fn push_u16(&mut self, value: u16) {
-        let [lsb, msb] = value.to_le_bytes();
-        self.push_u8(msb);
-        self.push_u8(lsb);
+    fn stack_address(&self) -> u16 {
+        0x100 | self.s as u16
     }
 
-    pub fn pop_u8(&mut self) -> u8 {
-        self.s += 1;
-        self.read(0x100 + self.s as u16)
+    fn push(&mut self, value: u8) {
+        if self.sequence == Sequence::Reset {
+            // The write line is held high during reset
+            self.read(self.stack_address());
+        } else {
+            self.write(self.stack_address(), value);
+        }
+        self.s = self.s.wrapping_sub(1);
     }
+}
 
-    pub fn pop_u16(&mut self) -> u16 {
-        let lsb = self.pop_u8();
-        let msb = self.pop_u8();
-        u16::from_le_bytes([lsb, msb])
+fn access(instruction: Instruction) -> Access {
+    match instruction {
+        Instruction::STA
+        | Instruction::STX
+        | Instruction::STY
+        | Instruction::SAX
+        | Instruction::SHA
+        | Instruction::SHX
+        | Instruction::SHY
+        | Instruction::TAS => Access::Write,
+        Instruction::ASL
+        | Instruction::DEC
+        | Instruction::INC
+        | Instruction::LSR
+        | Instruction::ROL
+        | Instruction::ROR
+        | Instruction::DCP
+        | Instruction::ISC
+        | Instruction::RLA
+        | Instruction::RRA
+        | Instruction::SLO
+        | Instruction::SRE => Access::ReadModifyWrite,
+        _ => Access::Read,
     }
 }
@@ -0,0 +1,400 @@
+use std::fmt::{self, Display, Formatter};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::{error, fs, io};
+
+use crate::cartridge::Mirroring;
+use crate::header::{HeaderFormat, RomHeader, Timing};
+
+/// A header field that was corrected by the ROM database.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HeaderField {
+    Mapper,
+    Submapper,
+    Mirroring,
+    Battery,
+    PrgRam,
+    PrgNvram,
+    ChrRam,
+    ChrNvram,
+    Timing,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DatabaseError {
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl Display for DatabaseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid ROM database, line {}: {}",
+            self.line, self.message
+        )
+    }
+}
+
+impl error::Error for DatabaseError {}
+
+/// What the database knows about a dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GameInfo {
+    /// Checksums of the PRG ROM followed by the CHR ROM
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: u16,
+    pub submapper: u8,
+    /// `None` when the database doesn't say, e.g. mapper controlled mirroring
+    pub mirroring: Option<Mirroring>,
+    pub has_battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+}
+
+impl GameInfo {
+    /// Rewrites `header` as the NES 2.0 header of this game.
+    /// Returns the fields whose value changed.
+    pub fn correct(&self, header: &mut RomHeader) -> Vec<HeaderField> {
+        let original = header.clone();
+
+        header.format = HeaderFormat::Nes2;
+        header.mapper = self.mapper;
+        header.submapper = self.submapper;
+        header.mirroring = self.mirroring.unwrap_or(header.mirroring);
+        header.has_battery = self.has_battery;
+        header.prg_ram_shift = size_to_shift(self.prg_ram_size);
+        header.prg_nvram_shift = size_to_shift(self.prg_nvram_size);
+        header.chr_ram_shift = size_to_shift(self.chr_ram_size);
+        header.chr_nvram_shift = size_to_shift(self.chr_nvram_size);
+        header.ines_prg_ram_units = 0;
+        header.timing = self.timing;
+
+        let fields = [
+            (HeaderField::Mapper, original.mapper != header.mapper),
+            (
+                HeaderField::Submapper,
+                original.submapper != header.submapper,
+            ),
+            (
+                HeaderField::Mirroring,
+                original.mirroring != header.mirroring,
+            ),
+            (
+                HeaderField::Battery,
+                original.has_battery != header.has_battery,
+            ),
+            (
+                HeaderField::PrgRam,
+                original.prg_ram_size() != header.prg_ram_size(),
+            ),
+            (
+                HeaderField::PrgNvram,
+                original.prg_nvram_size() != header.prg_nvram_size(),
+            ),
+            (
+                HeaderField::ChrRam,
+                original.chr_ram_size() != header.chr_ram_size(),
+            ),
+            (
+                HeaderField::ChrNvram,
+                original.chr_nvram_size() != header.chr_nvram_size(),
+            ),
+            (HeaderField::Timing, original.timing != header.timing),
+        ];
+        fields
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|&(field, _)| field)
+            .collect()
+    }
+}
+
+/// Game database in the NES 2.0 XML format.
+/// See https://forums.nesdev.org/viewtopic.php?t=19940
+#[derive(Debug, Default)]
+pub struct RomDatabase {
+    games: Vec<GameInfo>,
+}
+
+impl RomDatabase {
+    /// The database compiled into the emulator, misc/nes20db.xml. It only knows the test
+    /// ROMs of misc/, headers of a real collection are corrected by loading the complete
+    /// nes20db.xml with `from_file`.
+    pub fn builtin() -> &'static RomDatabase {
+        static DATABASE: OnceLock<RomDatabase> = OnceLock::new();
+        DATABASE.get_or_init(|| {
+            RomDatabase::parse(include_str!("../misc/nes20db.xml"))
+                .expect("The built-in ROM database is invalid")
+        })
+    }
+
+    /// Loads a database like the complete nes20db.xml, which is too big to embed.
+    /// An invalid database is an `InvalidData` error.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let xml = fs::read_to_string(path)?;
+        Self::parse(&xml).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(xml: &str) -> Result<Self, DatabaseError> {
+        let mut games = vec![];
+        let mut game: Option<GameBuilder> = None;
+
+        for tag in Tags::new(xml) {
+            let tag = tag?;
+            let error = |message| DatabaseError {
+                line: tag.line,
+                message,
+            };
+
+            match (tag.name, &mut game) {
+                ("game", None) if !tag.is_closing => game = Some(GameBuilder::default()),
+                ("game", Some(_)) if tag.is_closing => {
+                    let builder = game.take().unwrap();
+                    games.push(builder.build().ok_or_else(|| error("game without <rom>"))?);
+                }
+                ("game", _) => return Err(error("unexpected <game>")),
+                (_, Some(builder)) if !tag.is_closing => builder.element(&tag)?,
+                _ => {}
+            }
+        }
+
+        if game.is_some() {
+            return Err(DatabaseError {
+                line: xml.lines().count(),
+                message: "unterminated <game>",
+            });
+        }
+
+        Ok(Self { games })
+    }
+
+    pub fn len(&self) -> usize {
+        self.games.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.games.is_empty()
+    }
+
+    /// Finds a game by the checksums of its PRG ROM followed by its CHR ROM.
+    /// The SHA-1 is only compared when the database has one.
+    pub fn find(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&GameInfo> {
+        self.games.iter().find(|game| {
+            game.crc32 == crc32 && game.sha1.as_ref().is_none_or(|digest| digest == sha1)
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct GameBuilder {
+    crc32: Option<u32>,
+    sha1: Option<[u8; 20]>,
+    mapper: u16,
+    submapper: u8,
+    mirroring: Option<Mirroring>,
+    has_battery: bool,
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    chr_ram_size: usize,
+    chr_nvram_size: usize,
+    region: u8,
+}
+
+impl GameBuilder {
+    fn element(&mut self, tag: &Tag) -> Result<(), DatabaseError> {
+        match tag.name {
+            "rom" => {
+                self.crc32 = Some(tag.parse("crc32", |v| u32::from_str_radix(v, 16).ok())?);
+                if tag.attribute("sha1").is_some() {
+                    self.sha1 = Some(tag.parse("sha1", parse_sha1)?);
+                }
+            }
+            "prgram" => self.prg_ram_size = tag.parse("size", |v| v.parse().ok())?,
+            "prgnvram" => self.prg_nvram_size = tag.parse("size", |v| v.parse().ok())?,
+            "chrram" => self.chr_ram_size = tag.parse("size", |v| v.parse().ok())?,
+            "chrnvram" => self.chr_nvram_size = tag.parse("size", |v| v.parse().ok())?,
+            "pcb" => {
+                self.mapper = tag.parse("mapper", |v| v.parse().ok())?;
+                self.submapper = tag.parse("submapper", |v| v.parse().ok())?;
+                self.mirroring = match tag.attribute("mirroring") {
+                    Some("H") => Some(Mirroring::Horizontal),
+                    Some("V") => Some(Mirroring::Vertical),
+                    Some("4") => Some(Mirroring::FourScreen),
+                    _ => None,
+                };
+                self.has_battery = tag.attribute("battery") == Some("1");
+            }
+            "console" => self.region = tag.parse("region", |v| v.parse().ok())?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn build(self) -> Option<GameInfo> {
+        Some(GameInfo {
+            crc32: self.crc32?,
+            sha1: self.sha1,
+            mapper: self.mapper,
+            submapper: self.submapper,
+            mirroring: self.mirroring,
+            has_battery: self.has_battery,
+            prg_ram_size: self.prg_ram_size,
+            prg_nvram_size: self.prg_nvram_size,
+            chr_ram_size: self.chr_ram_size,
+            chr_nvram_size: self.chr_nvram_size,
+            timing: match self.region {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::Multi,
+                _ => Timing::Dendy,
+            },
+        })
+    }
+}
+
+/// NES 2.0 RAM sizes are 64 << shift bytes, rounded up.
+fn size_to_shift(size: usize) -> u8 {
+    if size == 0 {
+        0
+    } else {
+        (size.div_ceil(64).next_power_of_two().trailing_zeros() as u8).max(1)
+    }
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// An XML start, end or empty element tag.
+#[derive(Debug)]
+struct Tag<'a> {
+    name: &'a str,
+    attributes: Vec<(&'a str, &'a str)>,
+    is_closing: bool,
+    line: usize,
+}
+
+impl<'a> Tag<'a> {
+    fn attribute(&self, name: &str) -> Option<&'a str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|&(_, value)| value)
+    }
+
+    fn parse<T>(&self, name: &str, parse: impl Fn(&str) -> Option<T>) -> Result<T, DatabaseError> {
+        let error = |message| DatabaseError {
+            line: self.line,
+            message,
+        };
+        let value = self
+            .attribute(name)
+            .ok_or_else(|| error("missing attribute"))?;
+        parse(value).ok_or_else(|| error("invalid attribute value"))
+    }
+}
+
+/// Just enough of an XML tokenizer for the database: tags and their attributes,
+/// skipping text, comments, the XML declaration and entities.
+struct Tags<'a> {
+    xml: &'a str,
+    position: usize,
+}
+
+impl<'a> Tags<'a> {
+    fn new(xml: &'a str) -> Self {
+        Self { xml, position: 0 }
+    }
+
+    fn line(&self, position: usize) -> usize {
+        self.xml[..position].matches('\n').count() + 1
+    }
+
+    fn parse_tag(&self, start: usize, body: &'a str) -> Result<Tag<'a>, DatabaseError> {
+        let line = self.line(start);
+        let error = |message| DatabaseError { line, message };
+
+        let is_closing = body.starts_with('/');
+        let body = body.trim_start_matches('/').trim_end_matches('/');
+        let name_end = body.find(char::is_whitespace).unwrap_or(body.len());
+        let (name, mut rest) = body.split_at(name_end);
+        if name.is_empty() {
+            return Err(error("missing tag name"));
+        }
+
+        let mut attributes = vec![];
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+            let equals = rest.find('=').ok_or_else(|| error("missing '='"))?;
+            let key = rest[..equals].trim_end();
+            let value = rest[equals + 1..].trim_start();
+            let quote = value
+                .chars()
+                .next()
+                .filter(|&c| c == '"' || c == '\'')
+                .ok_or_else(|| error("unquoted attribute value"))?;
+            let end = value[1..]
+                .find(quote)
+                .ok_or_else(|| error("unterminated attribute value"))?;
+            attributes.push((key, &value[1..end + 1]));
+            rest = &value[end + 2..];
+        }
+
+        Ok(Tag {
+            name,
+            attributes,
+            is_closing,
+            line,
+        })
+    }
+}
+
+impl<'a> Iterator for Tags<'a> {
+    type Item = Result<Tag<'a>, DatabaseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.position + self.xml[self.position..].find('<')?;
+            let rest = &self.xml[start..];
+
+            let (terminator, skip) = if rest.starts_with("<!--") {
+                ("-->", true)
+            } else if rest.starts_with("<?") || rest.starts_with("<!") {
+                (">", true)
+            } else {
+                (">", false)
+            };
+
+            let end = match rest.find(terminator) {
+                Some(end) => start + end,
+                None => {
+                    self.position = self.xml.len();
+                    return Some(Err(DatabaseError {
+                        line: self.line(start),
+                        message: "unterminated tag",
+                    }));
+                }
+            };
+            self.position = end + terminator.len();
+
+            if !skip {
+                return Some(self.parse_tag(start, &self.xml[start + 1..end]));
+            }
+        }
+    }
+}
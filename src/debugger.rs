@@ -0,0 +1,590 @@
+use std::error;
+use std::fmt::{self, Display, Formatter, Write};
+
+use crate::breakpoints::{
+    parse_number, Access, Breakpoint, BreakpointKind, Condition, ConditionError,
+};
+use crate::bus::Bus;
+use crate::flags::CPUFlags;
+use crate::nes::{Nes, StopReason};
+
+const JSR: u8 = 0x20;
+const NMI_VECTOR: u16 = 0xFFFA;
+
+pub const HELP: &str = "\
+Numbers are decimal, or hexadecimal with a $ or 0x prefix.
+  step [n]                  run n instructions (s)
+  next [n]                  run n instructions, stepping over subroutines and interrupts (n)
+  finish                    run until the current subroutine or interrupt handler returns
+  continue                  run until a breakpoint is hit or the CPU jams (c)
+  frame [n]                 run n video frames
+  reset                     press the reset button
+  break <addr> [if <cond>]  stop before running the instruction at addr (b)
+  watch <addr>[-<end>] [if <cond>]
+                            stop after writes to the range, rwatch for reads, awatch for both
+  catch brk [off]           stop on BRK instructions, or stop ignoring them
+  delete [id]               delete a breakpoint, or all of them (d)
+  enable <id>, disable <id>
+  ignore <id> <count>       ignore the next count hits of a breakpoint
+  breakpoints               list the breakpoints (info)
+  registers                 show the registers and flags (regs)
+  set <reg> <value>         set a, x, y, s, pc, p or a flag: c, z, i, d, v, n
+  memory <addr> [len]       hexdump memory (x)
+  poke <addr> <byte>...     write bytes to memory like the CPU does
+  disassemble [addr] [n]    disassemble n instructions from addr, or around PC (dis)
+  backtrace                 show the subroutines and interrupt handlers running (bt)
+Conditions look like `a == $10 && !c`, `value` is the byte read or written.";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandError {
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    InvalidNumber(String),
+    /// A range whose end is before its start
+    InvalidRange(String),
+    UnknownRegister(String),
+    InvalidCondition(ConditionError),
+    NoBreakpoint(usize),
+    /// `finish` outside of any subroutine
+    NoFrame,
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownCommand(c) => {
+                write!(f, "Unknown command \"{}\", try \"help\"", c)
+            }
+            CommandError::MissingArgument(a) => write!(f, "Missing argument: {}", a),
+            CommandError::InvalidNumber(n) => write!(f, "Invalid number \"{}\"", n),
+            CommandError::InvalidRange(r) => write!(f, "Invalid address range \"{}\"", r),
+            CommandError::UnknownRegister(r) => write!(f, "Unknown register \"{}\"", r),
+            CommandError::InvalidCondition(e) => e.fmt(f),
+            CommandError::NoBreakpoint(id) => write!(f, "No breakpoint {}", id),
+            CommandError::NoFrame => write!(f, "Not in a subroutine"),
+        }
+    }
+}
+
+impl error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CommandError::InvalidCondition(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ConditionError> for CommandError {
+    fn from(e: ConditionError) -> Self {
+        CommandError::InvalidCondition(e)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameKind {
+    Subroutine,
+    Nmi,
+    Irq,
+    Brk,
+}
+
+/// A subroutine or interrupt handler that hasn't returned yet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The JSR, or the instruction the interrupt happened before
+    pub call_site: u16,
+    /// First instruction of the subroutine or handler
+    pub entry: u16,
+    /// S before the call, the frame has returned once S is back to it
+    pub s: u8,
+}
+
+/// Runs the console one instruction at a time for the debugger commands,
+/// reconstructing the call stack from the JSRs, interrupts and returns.
+#[derive(Debug)]
+pub struct Debugger {
+    pub nes: Nes,
+    frames: Vec<Frame>,
+}
+
+impl Debugger {
+    pub fn new(nes: Nes) -> Self {
+        Self {
+            nes,
+            frames: Vec::new(),
+        }
+    }
+
+    /// The frames running, the innermost last.
+    pub fn backtrace(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Runs an instruction, or an interrupt sequence, and tracks the calls.
+    pub fn step(&mut self) -> StopReason {
+        let pc = self.nes.cpu.pc;
+        let s = self.nes.cpu.s;
+        let opcode = self.nes.cpu.peek(pc);
+        let reason = self.nes.step_instruction();
+
+        let cpu = &self.nes.cpu;
+        let kind = match s.wrapping_sub(cpu.s) {
+            2 if opcode == JSR => Some(FrameKind::Subroutine),
+            // Interrupts are taken instead of the instruction at PC
+            3 if cpu.pc == cpu.peek_u16(NMI_VECTOR) => Some(FrameKind::Nmi),
+            3 if opcode == 0x00 => Some(FrameKind::Brk),
+            3 => Some(FrameKind::Irq),
+            _ => None,
+        };
+        match kind {
+            Some(kind) => self.frames.push(Frame {
+                kind,
+                call_site: pc,
+                entry: cpu.pc,
+                s,
+            }),
+            None => {
+                // RTS, RTI, or anything else moving S back up like TXS
+                while matches!(self.frames.last(), Some(frame) if cpu.s >= frame.s) {
+                    self.frames.pop();
+                }
+            }
+        }
+        reason
+    }
+
+    /// Steps until something stops the CPU or `done` is true.
+    pub fn run_until<F>(&mut self, mut done: F) -> StopReason
+    where
+        F: FnMut(&mut Debugger) -> bool,
+    {
+        loop {
+            let reason = self.step();
+            if reason != StopReason::Done {
+                return reason;
+            }
+            if done(self) {
+                return StopReason::Condition;
+            }
+        }
+    }
+
+    /// Runs an instruction, and the subroutine or interrupt handler it enters.
+    pub fn step_over(&mut self) -> StopReason {
+        let depth = self.frames.len();
+        let reason = self.step();
+        if reason != StopReason::Done || self.frames.len() <= depth {
+            return reason;
+        }
+        match self.run_until(|debugger| debugger.frames.len() <= depth) {
+            StopReason::Condition => StopReason::Done,
+            reason => reason,
+        }
+    }
+
+    /// Runs until the innermost frame returns.
+    pub fn finish(&mut self) -> Result<StopReason, CommandError> {
+        let depth = self
+            .frames
+            .len()
+            .checked_sub(1)
+            .ok_or(CommandError::NoFrame)?;
+        Ok(self.run_until(|debugger| debugger.frames.len() <= depth))
+    }
+
+    pub fn reset(&mut self) {
+        self.nes.reset();
+        self.frames.clear();
+    }
+
+    /// Runs a command line, returns what to print.
+    pub fn execute(&mut self, line: &str) -> Result<String, CommandError> {
+        // Conditions have spaces, they go until the end of the line
+        let (line, condition) = match line.find(" if ") {
+            Some(i) => (&line[..i], Some(Condition::parse(&line[i + 4..])?)),
+            None => (line, None),
+        };
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(String::new()),
+        };
+        let args: Vec<&str> = words.collect();
+
+        match command {
+            "step" | "s" => {
+                let mut reason = StopReason::Done;
+                for _ in 0..count(&args, 0)? {
+                    reason = self.step();
+                    if reason != StopReason::Done {
+                        break;
+                    }
+                }
+                Ok(self.stopped(reason))
+            }
+            "next" | "n" => {
+                let mut reason = StopReason::Done;
+                for _ in 0..count(&args, 0)? {
+                    reason = self.step_over();
+                    if reason != StopReason::Done {
+                        break;
+                    }
+                }
+                Ok(self.stopped(reason))
+            }
+            "finish" => {
+                let reason = self.finish()?;
+                Ok(self.stopped(reason))
+            }
+            "continue" | "c" => {
+                let reason = self.run_until(|_| false);
+                Ok(self.stopped(reason))
+            }
+            "frame" => {
+                let mut frames = count(&args, 0)?;
+                if frames == 0 {
+                    return Ok(self.stopped(StopReason::Done));
+                }
+                // Only the frames completed from now count
+                self.nes.cpu.bus.ppu.poll_frame_complete();
+                let reason = self.run_until(|debugger| {
+                    if debugger.nes.cpu.bus.ppu.poll_frame_complete() {
+                        frames -= 1;
+                    }
+                    frames == 0
+                });
+                Ok(self.stopped(reason))
+            }
+            "reset" => {
+                self.reset();
+                Ok(self.stopped(StopReason::Done))
+            }
+            "break" | "b" => {
+                let address = number(&args, 0, "address")?;
+                let mut breakpoint = Breakpoint::execute(address);
+                breakpoint.condition = condition;
+                let id = self.nes.cpu.breakpoints.add(breakpoint);
+                Ok(format!("Breakpoint {} at ${:04X}", id, address))
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match command {
+                    "watch" => BreakpointKind::Write,
+                    "rwatch" => BreakpointKind::Read,
+                    _ => BreakpointKind::ReadWrite,
+                };
+                let range = args
+                    .first()
+                    .ok_or(CommandError::MissingArgument("address"))?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse(start)?, parse(end)?),
+                    None => (parse(range)?, parse(range)?),
+                };
+                if start > end {
+                    return Err(CommandError::InvalidRange(range.to_string()));
+                }
+                let mut breakpoint = Breakpoint::new(kind, start..=end);
+                breakpoint.condition = condition;
+                let id = self.nes.cpu.breakpoints.add(breakpoint);
+                Ok(format!(
+                    "Watchpoint {} on {}",
+                    id,
+                    describe_range(kind, start, end)
+                ))
+            }
+            "catch" => {
+                let enable = match (args.first(), args.get(1)) {
+                    (Some(&"brk"), None | Some(&"on")) => true,
+                    (Some(&"brk"), Some(&"off")) => false,
+                    (None, _) => return Err(CommandError::MissingArgument("event")),
+                    _ => return Err(CommandError::UnknownCommand(line.trim().to_string())),
+                };
+                self.nes.cpu.break_on_brk = enable;
+                Ok(if enable {
+                    "Stopping on BRK".to_string()
+                } else {
+                    "Not stopping on BRK".to_string()
+                })
+            }
+            "delete" | "d" => {
+                if args.is_empty() {
+                    self.nes.cpu.breakpoints.clear();
+                    return Ok("Deleted all breakpoints".to_string());
+                }
+                let id = id(&args)?;
+                self.nes
+                    .cpu
+                    .breakpoints
+                    .remove(id)
+                    .ok_or(CommandError::NoBreakpoint(id))?;
+                Ok(format!("Deleted breakpoint {}", id))
+            }
+            "enable" | "disable" => {
+                let id = id(&args)?;
+                if !self
+                    .nes
+                    .cpu
+                    .breakpoints
+                    .set_enabled(id, command == "enable")
+                {
+                    return Err(CommandError::NoBreakpoint(id));
+                }
+                Ok(format!("Breakpoint {} {}d", id, command))
+            }
+            "ignore" => {
+                let id = id(&args)?;
+                let count = number(&args, 1, "count")? as u64;
+                let breakpoints = &mut self.nes.cpu.breakpoints;
+                let hits = breakpoints
+                    .get(id)
+                    .ok_or(CommandError::NoBreakpoint(id))?
+                    .hits;
+                breakpoints.set_ignore_count(id, hits + count);
+                Ok(format!(
+                    "Ignoring the next {} hits of breakpoint {}",
+                    count, id
+                ))
+            }
+            "breakpoints" | "info" => Ok(self.list_breakpoints()),
+            "registers" | "regs" => Ok(self.registers()),
+            "set" => {
+                let register = args
+                    .first()
+                    .ok_or(CommandError::MissingArgument("register"))?;
+                let value = number(&args, 1, "value")?;
+                self.set_register(register, value)?;
+                Ok(self.registers())
+            }
+            "memory" | "x" => {
+                let address = number(&args, 0, "address")?;
+                let length = match args.get(1) {
+                    Some(length) => parse(length)?,
+                    None => 64,
+                };
+                Ok(self.hexdump(address, length))
+            }
+            "poke" => {
+                let address = number(&args, 0, "address")?;
+                if args.len() < 2 {
+                    return Err(CommandError::MissingArgument("bytes"));
+                }
+                for (i, byte) in args[1..].iter().enumerate() {
+                    let byte = parse(byte)?;
+                    self.nes
+                        .cpu
+                        .bus
+                        .write(address.wrapping_add(i as u16), byte as u8);
+                }
+                Ok(self.hexdump(address, args.len() as u16 - 1))
+            }
+            "disassemble" | "dis" => {
+                let count = match args.get(1) {
+                    Some(count) => parse(count)? as usize,
+                    None => 10,
+                };
+                let lines = match args.first() {
+                    Some(address) => self.nes.cpu.disassemble_range(parse(address)?, count),
+                    None => self.nes.cpu.disassemble_around(self.nes.cpu.pc, 4, 5),
+                };
+                let pc = self.nes.cpu.pc;
+                let text: Vec<String> = lines
+                    .iter()
+                    .map(|line| {
+                        let marker = if line.address == pc { "=>" } else { "  " };
+                        format!("{} {}", marker, line)
+                    })
+                    .collect();
+                Ok(text.join("\n"))
+            }
+            "backtrace" | "bt" => Ok(self.format_backtrace()),
+            "help" | "h" => Ok(HELP.to_string()),
+            _ => Err(CommandError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    /// Why the CPU stopped and where.
+    fn stopped(&self, reason: StopReason) -> String {
+        let mut text = String::new();
+        match reason {
+            StopReason::Done | StopReason::Condition => {}
+            StopReason::Jammed { pc, opcode } => {
+                writeln!(text, "CPU jammed by opcode ${:02X} at ${:04X}", opcode, pc).unwrap()
+            }
+            StopReason::Break { pc } => writeln!(text, "BRK at ${:04X}", pc).unwrap(),
+            StopReason::Breakpoint(hit) => match hit.access {
+                Access::Execute => {
+                    writeln!(text, "Breakpoint {} at ${:04X}", hit.id, hit.address).unwrap()
+                }
+                Access::Read => writeln!(
+                    text,
+                    "Watchpoint {}: read ${:02X} from ${:04X}",
+                    hit.id, hit.value, hit.address
+                )
+                .unwrap(),
+                Access::Write => writeln!(
+                    text,
+                    "Watchpoint {}: wrote ${:02X} to ${:04X}",
+                    hit.id, hit.value, hit.address
+                )
+                .unwrap(),
+            },
+        }
+        write!(text, "=> {}", self.nes.cpu.disassemble(self.nes.cpu.pc)).unwrap();
+        text
+    }
+
+    fn list_breakpoints(&self) -> String {
+        let breakpoints = &self.nes.cpu.breakpoints;
+        if breakpoints.is_empty() {
+            return "No breakpoints".to_string();
+        }
+        let lines: Vec<String> = breakpoints
+            .iter()
+            .map(|(id, breakpoint)| {
+                let range = &breakpoint.addresses;
+                let mut line = format!(
+                    "{:<3} {:<8} {}",
+                    id,
+                    if breakpoint.enabled {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    },
+                    describe_range(breakpoint.kind, *range.start(), *range.end())
+                );
+                write!(line, ", hits: {}", breakpoint.hits).unwrap();
+                if breakpoint.ignore_count > breakpoint.hits {
+                    write!(
+                        line,
+                        ", ignoring {} more",
+                        breakpoint.ignore_count - breakpoint.hits
+                    )
+                    .unwrap();
+                }
+                if let Some(condition) = &breakpoint.condition {
+                    write!(line, ", if {}", condition).unwrap();
+                }
+                line
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    fn registers(&self) -> String {
+        let cpu = &self.nes.cpu;
+        let p = cpu.flags.to_byte();
+        let flags: String = "nv-bdizc"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if c != '-' && p & (0x80 >> i) != 0 {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect();
+        format!(
+            "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} PC:{:04X} P:{:02X} {} CYC:{}",
+            cpu.a, cpu.x, cpu.y, cpu.s, cpu.pc, p, flags, cpu.cycles
+        )
+    }
+
+    fn set_register(&mut self, register: &str, value: u16) -> Result<(), CommandError> {
+        let cpu = &mut self.nes.cpu;
+        let flag = value != 0;
+        match register.to_ascii_lowercase().as_str() {
+            "a" => cpu.a = value as u8,
+            "x" => cpu.x = value as u8,
+            "y" => cpu.y = value as u8,
+            "s" | "sp" => cpu.s = value as u8,
+            "pc" => cpu.pc = value,
+            "p" => cpu.flags = CPUFlags::from_byte(value as u8),
+            "c" => cpu.flags.carry = flag,
+            "z" => cpu.flags.zero = flag,
+            "i" => cpu.flags.interrupt_disable = flag,
+            "d" => cpu.flags.decimal_mode = flag,
+            "v" => cpu.flags.overflow = flag,
+            "n" => cpu.flags.negative = flag,
+            _ => return Err(CommandError::UnknownRegister(register.to_string())),
+        }
+        Ok(())
+    }
+
+    /// 16 bytes per line, without side effects on the registers mapped there.
+    fn hexdump(&self, address: u16, length: u16) -> String {
+        let mut lines = Vec::new();
+        for line in (0..length).step_by(16) {
+            let start = address.wrapping_add(line);
+            let bytes: Vec<u8> = (0..(length - line).min(16))
+                .map(|i| self.nes.cpu.peek(start.wrapping_add(i)))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let ascii: String = bytes
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            lines.push(format!("{:04X}  {:<47}  |{}|", start, hex.join(" "), ascii));
+        }
+        lines.join("\n")
+    }
+
+    fn format_backtrace(&self) -> String {
+        // The innermost frame first, where the CPU is
+        let mut pc = self.nes.cpu.pc;
+        let mut lines = Vec::new();
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Subroutine => "",
+                FrameKind::Nmi => " <NMI>",
+                FrameKind::Irq => " <IRQ>",
+                FrameKind::Brk => " <BRK>",
+            };
+            lines.push(format!(
+                "#{:<3} ${:04X} in ${:04X}{}",
+                i, pc, frame.entry, kind
+            ));
+            pc = frame.call_site;
+        }
+        lines.push(format!("#{:<3} ${:04X}", self.frames.len(), pc));
+        lines.join("\n")
+    }
+}
+
+fn describe_range(kind: BreakpointKind, start: u16, end: u16) -> String {
+    let kind = match kind {
+        BreakpointKind::Execute => "execution of",
+        BreakpointKind::Read => "reads from",
+        BreakpointKind::Write => "writes to",
+        BreakpointKind::ReadWrite => "accesses to",
+    };
+    if start == end {
+        format!("{} ${:04X}", kind, start)
+    } else {
+        format!("{} ${:04X}-${:04X}", kind, start, end)
+    }
+}
+
+fn parse(word: &str) -> Result<u16, CommandError> {
+    parse_number(word).ok_or_else(|| CommandError::InvalidNumber(word.to_string()))
+}
+
+fn number(args: &[&str], index: usize, name: &'static str) -> Result<u16, CommandError> {
+    parse(args.get(index).ok_or(CommandError::MissingArgument(name))?)
+}
+
+/// Optional repeat count, 1 by default.
+fn count(args: &[&str], index: usize) -> Result<u16, CommandError> {
+    args.get(index).map_or(Ok(1), |count| parse(count))
+}
+
+fn id(args: &[&str]) -> Result<usize, CommandError> {
+    number(args, 0, "breakpoint id").map(|id| id as usize)
+}
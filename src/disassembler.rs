@@ -1,86 +1,194 @@
-use std::fmt::Write;
+use std::fmt::{self, Display, Formatter, Write};
 
+use crate::bus::Bus;
 use crate::cpu::CPU;
-use crate::opcodes::{AddressingMode, Instruction, OPCODES};
+use crate::opcodes::{is_unofficial, AddressingMode, Instruction, OPCODES};
+
+/// An instruction decoded from memory without running it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disassembly {
+    pub address: u16,
+    /// Opcode and operands
+    pub bytes: Vec<u8>,
+    /// e.g. `LDA $0200,X`, unofficial opcodes are marked with a star like in nestest's log
+    pub text: String,
+}
+
+impl Disassembly {
+    /// Address of the instruction that follows.
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+impl Display for Disassembly {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(
+            f,
+            "{:04X}  {:<10}{}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Decodes the instruction at `address`. Unlike the log, operands aren't resolved
+    /// with the registers, so any address can be disassembled.
+    pub fn disassemble(&self, address: u16) -> Disassembly {
+        let op = self.peek(address);
+        let (instruction, addressing_mode, _) = OPCODES[op as usize];
+        // BRK skips the signature byte following it, the return address is after it
+        let length = match instruction {
+            Instruction::BRK => 2,
+            _ => addressing_mode.instruction_length(),
+        };
+        let bytes: Vec<u8> = (0..length)
+            .map(|i| self.peek(address.wrapping_add(i)))
+            .collect();
+        let byte = || bytes[1];
+        let word = || u16::from_le_bytes([bytes[1], bytes[2]]);
+
+        let operand = match addressing_mode {
+            AddressingMode::Implicit if instruction == Instruction::BRK => {
+                format!("#${:02X}", byte())
+            }
+            AddressingMode::Implicit => String::new(),
+            AddressingMode::Accumulator => "A".to_string(),
+            AddressingMode::Immediate => format!("#${:02X}", byte()),
+            AddressingMode::ZeroPage => format!("${:02X}", byte()),
+            AddressingMode::ZeroPageIndexedX => format!("${:02X},X", byte()),
+            AddressingMode::ZeroPageIndexedY => format!("${:02X},Y", byte()),
+            AddressingMode::Relative => format!(
+                "${:04X}",
+                address.wrapping_add(2).wrapping_add(byte() as i8 as u16)
+            ),
+            AddressingMode::Absolute => format!("${:04X}", word()),
+            AddressingMode::AbsoluteIndexedX => format!("${:04X},X", word()),
+            AddressingMode::AbsoluteIndexedY => format!("${:04X},Y", word()),
+            AddressingMode::Indirect => format!("(${:04X})", word()),
+            AddressingMode::IndexedIndirect => format!("(${:02X},X)", byte()),
+            AddressingMode::IndirectIndexed => format!("(${:02X}),Y", byte()),
+        };
+
+        let star = if is_unofficial(op) { "*" } else { "" };
+        let text = format!("{}{:?} {}", star, instruction, operand);
+        Disassembly {
+            address,
+            text: text.trim_end().to_string(),
+            bytes,
+        }
+    }
+
+    /// Disassembles `count` instructions from `address`.
+    pub fn disassemble_range(&self, address: u16, count: usize) -> Vec<Disassembly> {
+        let mut address = address;
+        (0..count)
+            .map(|_| {
+                let disassembly = self.disassemble(address);
+                address = disassembly.next_address();
+                disassembly
+            })
+            .collect()
+    }
+
+    /// Disassembles up to `before` instructions leading to `address`, the one at `address`
+    /// and `after` more. Instructions have different lengths so the ones before are a guess:
+    /// the first start address, from the furthest, whose instructions fall on `address`.
+    pub fn disassemble_around(
+        &self,
+        address: u16,
+        before: usize,
+        after: usize,
+    ) -> Vec<Disassembly> {
+        let mut lines = Vec::new();
+        for distance in (1..=3 * before as u16).rev() {
+            let mut start = address.wrapping_sub(distance);
+            let mut candidate = Vec::new();
+            // Distance to `address`, without wrapping past it
+            while address.wrapping_sub(start) <= distance && start != address {
+                let disassembly = self.disassemble(start);
+                start = disassembly.next_address();
+                candidate.push(disassembly);
+            }
+            if start == address {
+                lines = candidate;
+                break;
+            }
+        }
+        let skip = lines.len().saturating_sub(before);
+        lines.drain(..skip);
+        lines.extend(self.disassemble_range(address, after + 1));
+        lines
+    }
 
-impl CPU {
     pub fn disassemble_and_log_current_instruction(&mut self) {
-        let op = self.read(self.pc);
+        let op = self.peek(self.pc);
         let (instruction, addressing_mode, _) = OPCODES[op as usize];
 
         write!(self.logs, "{:04X}  ", self.pc).unwrap();
 
-        let bytes = match addressing_mode {
-            AddressingMode::Implicit => 1,
-            AddressingMode::Accumulator => 1,
-            AddressingMode::Immediate => 2,
-            AddressingMode::ZeroPage => 2,
-            AddressingMode::ZeroPageIndexedX => 2,
-            AddressingMode::ZeroPageIndexedY => 2,
-            AddressingMode::Relative => 2,
-            AddressingMode::Absolute => 3,
-            AddressingMode::AbsoluteIndexedX => 3,
-            AddressingMode::AbsoluteIndexedY => 3,
-            AddressingMode::Indirect => 3,
-            AddressingMode::IndexedIndirect => 2,
-            AddressingMode::IndirectIndexed => 2,
-        };
+        let bytes = addressing_mode.instruction_length();
 
         let mut bytes_str = String::new();
         for i in 0..bytes {
-            bytes_str += &format!("{:02X} ", self.read(self.pc + i));
+            bytes_str += &format!("{:02X} ", self.peek(self.pc.wrapping_add(i)));
         }
-        write!(self.logs, "{:<10}", bytes_str).unwrap();
+        write!(self.logs, "{:<9}", bytes_str).unwrap();
 
-        write!(self.logs, "{:?} ", instruction).unwrap();
+        // Like nestest's log, unofficial opcodes are marked with a star
+        let star = if is_unofficial(op) { '*' } else { ' ' };
+        write!(self.logs, "{}{:?} ", star, instruction).unwrap();
 
-        let pc = self.pc + 1;
+        let pc = self.pc.wrapping_add(1);
         let arg = match addressing_mode {
             AddressingMode::Implicit => String::new(),
             AddressingMode::Accumulator => "A".to_string(),
-            AddressingMode::Immediate => format!("#${:02X}", self.read(pc)),
+            AddressingMode::Immediate => format!("#${:02X}", self.peek(pc)),
             AddressingMode::ZeroPage => {
-                let addr = self.read(pc);
-                format!("${:02X} = {:02X}", addr, self.read(addr as u16))
+                let addr = self.peek(pc);
+                format!("${:02X} = {:02X}", addr, self.peek(addr as u16))
             }
             AddressingMode::ZeroPageIndexedX => {
-                let addr = self.read(pc);
+                let addr = self.peek(pc);
                 let addr_plus_x = addr.wrapping_add(self.x);
                 format!(
                     "${:02X},X @ {:02X} = {:02X}",
                     addr,
                     addr_plus_x,
-                    self.read(addr_plus_x as u16)
+                    self.peek(addr_plus_x as u16)
                 )
             }
             AddressingMode::ZeroPageIndexedY => {
-                let addr = self.read(pc);
+                let addr = self.peek(pc);
                 let addr_plus_y = addr.wrapping_add(self.y);
                 format!(
                     "${:02X},Y @ {:02X} = {:02X}",
                     addr,
                     addr_plus_y,
-                    self.read(addr_plus_y as u16)
+                    self.peek(addr_plus_y as u16)
                 )
             }
-            AddressingMode::Relative => format!("${:04X}", pc + 1 + self.read(pc) as i8 as u16),
+            AddressingMode::Relative => format!(
+                "${:04X}",
+                pc.wrapping_add(1).wrapping_add(self.peek(pc) as i8 as u16)
+            ),
             AddressingMode::Absolute => {
-                let is_jump_instruction = match instruction {
-                    Instruction::JSR => true,
-                    Instruction::JMP => true,
-                    _ => false,
-                };
+                let is_jump_instruction =
+                    matches!(instruction, Instruction::JSR | Instruction::JMP);
                 if !is_jump_instruction {
-                    let addr = self.read_u16(pc);
-                    format!("${:04X} = {:02X}", self.read_u16(pc), self.read(addr))
+                    let addr = self.peek_u16(pc);
+                    format!("${:04X} = {:02X}", self.peek_u16(pc), self.peek(addr))
                 } else {
-                    format!("${:04X}", self.read_u16(pc))
+                    format!("${:04X}", self.peek_u16(pc))
                 }
             }
             AddressingMode::AbsoluteIndexedX => {
                 let addr = {
-                    let lsb = self.read(pc);
-                    let msb = self.read(pc + 1);
+                    let lsb = self.peek(pc);
+                    let msb = self.peek(pc.wrapping_add(1));
                     u16::from_le_bytes([lsb, msb])
                 };
                 let addr_plus_x = addr.wrapping_add(self.x as u16);
@@ -88,13 +196,13 @@ impl CPU {
                     "${:04X},X @ {:04X} = {:02X}",
                     addr,
                     addr_plus_x,
-                    self.read(addr_plus_x)
+                    self.peek(addr_plus_x)
                 )
             }
             AddressingMode::AbsoluteIndexedY => {
                 let addr = {
-                    let lsb = self.read(pc);
-                    let msb = self.read(pc + 1);
+                    let lsb = self.peek(pc);
+                    let msb = self.peek(pc.wrapping_add(1));
                     u16::from_le_bytes([lsb, msb])
                 };
                 let addr_plus_y = addr.wrapping_add(self.y as u16);
@@ -102,35 +210,35 @@ impl CPU {
                     "${:04X},Y @ {:04X} = {:02X}",
                     addr,
                     addr_plus_y,
-                    self.read(addr_plus_y)
+                    self.peek(addr_plus_y)
                 )
             }
             AddressingMode::Indirect => {
                 let (addr, lsb) = {
-                    let lsb = self.read(pc);
-                    let msb = self.read(pc + 1);
+                    let lsb = self.peek(pc);
+                    let msb = self.peek(pc.wrapping_add(1));
                     (u16::from_le_bytes([lsb, msb]), lsb)
                 };
                 let indirect_addr = {
                     // Hardware bug
                     if lsb == 0xFF {
-                        let lsb = self.read(addr);
-                        let msb = self.read(addr & 0xFF00);
+                        let lsb = self.peek(addr);
+                        let msb = self.peek(addr & 0xFF00);
                         u16::from_le_bytes([lsb, msb])
                     } else {
-                        let lsb = self.read(addr);
-                        let msb = self.read(addr + 1);
+                        let lsb = self.peek(addr);
+                        let msb = self.peek(addr.wrapping_add(1));
                         u16::from_le_bytes([lsb, msb])
                     }
                 };
                 format!("(${:04X}) = {:04X}", addr, indirect_addr)
             }
             AddressingMode::IndexedIndirect => {
-                let arg = self.read(pc);
+                let arg = self.peek(pc);
                 let arg_plus_x = arg.wrapping_add(self.x);
                 let addr = {
-                    let lsb = self.read(arg.wrapping_add(self.x) as u16);
-                    let msb = self.read(arg.wrapping_add(self.x).wrapping_add(1) as u16);
+                    let lsb = self.peek(arg.wrapping_add(self.x) as u16);
+                    let msb = self.peek(arg.wrapping_add(self.x).wrapping_add(1) as u16);
                     u16::from_le_bytes([lsb, msb])
                 };
                 format!(
@@ -138,14 +246,14 @@ impl CPU {
                     arg,
                     arg_plus_x,
                     addr,
-                    self.read(addr)
+                    self.peek(addr)
                 )
             }
             AddressingMode::IndirectIndexed => {
-                let arg = self.read(pc);
+                let arg = self.peek(pc);
                 let addr = {
-                    let lsb = self.read(arg as u16);
-                    let msb = self.read(arg.wrapping_add(1) as u16);
+                    let lsb = self.peek(arg as u16);
+                    let msb = self.peek(arg.wrapping_add(1) as u16);
                     u16::from_le_bytes([lsb, msb])
                 };
                 let addr_plus_y = addr.wrapping_add(self.y as u16);
@@ -154,7 +262,7 @@ impl CPU {
                     arg,
                     addr,
                     addr_plus_y,
-                    self.read(addr_plus_y)
+                    self.peek(addr_plus_y)
                 )
             }
         };
@@ -175,9 +283,9 @@ impl CPU {
         writeln!(self.logs).unwrap();
     }
 
-    pub fn read_u16(&mut self, address: u16) -> u16 {
-        let lsb = self.read(address);
-        let msb = self.read(address + 1);
+    pub fn peek_u16(&self, address: u16) -> u16 {
+        let lsb = self.peek(address);
+        let msb = self.peek(address.wrapping_add(1));
         u16::from_le_bytes([lsb, msb])
     }
 
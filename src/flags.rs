@@ -1,6 +1,6 @@
 use crate::util::BitOperations;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CPUFlags {
     pub carry: bool,
     pub zero: bool,
@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use crate::breakpoints::{Access, Breakpoint, BreakpointKind};
+use crate::bus::Bus;
+use crate::flags::CPUFlags;
+use crate::nes::{Nes, StopReason};
+
+/// Instructions run between two checks for a Ctrl-C from the client.
+const INTERRUPT_POLL_INTERVAL: u32 = 10_000;
+
+/// Largest packet data exchanged, advertised in the `qSupported` reply.
+const PACKET_SIZE: usize = 0x1000;
+
+/// GDB has no 6502 architecture, front-ends learn the registers from this description.
+/// The order is the one of the `g` and `p` packets.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nesmulator.6502">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="s" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Signals of the stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Listens on localhost and serves the first client to connect, until it detaches.
+pub fn listen(nes: &mut Nes, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(nes, stream).run()
+}
+
+/// A GDB remote serial protocol server debugging the CPU.
+/// See https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+pub struct GdbStub<'a> {
+    nes: &'a mut Nes,
+    stream: TcpStream,
+    /// Bytes received but not handled yet
+    input: Vec<u8>,
+    /// Kept until acknowledged, to send it again if the client asks for it
+    last_packet: Vec<u8>,
+    no_ack: bool,
+    /// The breakpoints of the CPU created by Z packets, by type, address and length
+    breakpoints: HashMap<(u8, u16, u32), usize>,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(nes: &'a mut Nes, stream: TcpStream) -> Self {
+        Self {
+            nes,
+            stream,
+            input: Vec::new(),
+            last_packet: Vec::new(),
+            no_ack: false,
+            breakpoints: HashMap::new(),
+        }
+    }
+
+    /// Answers packets until the client detaches, kills the target or disconnects.
+    /// A client disconnecting while the CPU runs is an `UnexpectedEof` error.
+    pub fn run(&mut self) -> io::Result<()> {
+        // Packets are small and each one waits for an answer
+        self.stream.set_nodelay(true)?;
+        let result = self.serve();
+        self.clear_breakpoints();
+        result
+    }
+
+    fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match packet.as_str() {
+                "D" => {
+                    self.send("OK")?;
+                    break;
+                }
+                "k" => break,
+                _ => {
+                    let reply = self.handle(&packet)?;
+                    self.send(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => ok(self.write_registers(args)),
+            "p" => match parse_hex(args) {
+                Some(register) => self.read_register(register as usize),
+                None => error(),
+            },
+            "P" => ok(args.split_once('=').and_then(|(register, value)| {
+                let register = parse_hex(register)? as usize;
+                self.write_register(register, &decode_hex(value)?)
+            })),
+            "m" => self.read_memory(args).unwrap_or_else(error),
+            "M" => ok(self.write_memory(args)),
+            "s" | "c" => {
+                if let Some(address) = parse_hex(args) {
+                    self.nes.cpu.pc = address as u16;
+                }
+                let reason = if command == "s" {
+                    Some(self.nes.step_instruction())
+                } else {
+                    self.resume()?
+                };
+                self.stop_reply(reason)
+            }
+            "Z" => ok(self.insert_breakpoint(args)),
+            "z" => ok(self.remove_breakpoint(args)),
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            // Unsupported, including the v packets: GDB falls back to the basic ones
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match range.split_once(',') {
+                Some((offset, length)) => {
+                    let offset = parse_hex(offset).unwrap_or(0) as usize;
+                    let length = parse_hex(length).unwrap_or(0) as usize;
+                    let xml = TARGET_XML.get(offset.min(TARGET_XML.len())..).unwrap_or("");
+                    if xml.len() <= length {
+                        format!("l{}", xml)
+                    } else {
+                        format!("m{}", &xml[..length])
+                    }
+                }
+                None => error(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            // Attached to an existing process, detaching shouldn't kill it
+            "qAttached" => "1".to_string(),
+            // A single thread
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Runs until a breakpoint, a jam or a Ctrl-C, which returns None.
+    fn resume(&mut self) -> io::Result<Option<StopReason>> {
+        loop {
+            for _ in 0..INTERRUPT_POLL_INTERVAL {
+                let reason = self.nes.step_instruction();
+                if reason != StopReason::Done {
+                    return Ok(Some(reason));
+                }
+            }
+            if self.poll_interrupt()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// None for a Ctrl-C.
+    fn stop_reply(&self, reason: Option<StopReason>) -> String {
+        let hit = match reason {
+            None => return format!("S{:02x}", SIGINT),
+            Some(StopReason::Jammed { .. }) => return format!("S{:02x}", SIGILL),
+            Some(StopReason::Breakpoint(hit)) => hit,
+            Some(_) => return format!("S{:02x}", SIGTRAP),
+        };
+        let kind = self.nes.cpu.breakpoints.get(hit.id).map(|b| b.kind);
+        let stop = match (kind, hit.access) {
+            (_, Access::Execute) => return format!("T{:02x}swbreak:;", SIGTRAP),
+            (Some(BreakpointKind::ReadWrite), _) => "awatch",
+            (_, Access::Read) => "rwatch",
+            (_, Access::Write) => "watch",
+        };
+        format!("T{:02x}{}:{:x};", SIGTRAP, stop, hit.address)
+    }
+
+    fn registers(&self) -> [u8; 7] {
+        let cpu = &self.nes.cpu;
+        let [pc_low, pc_high] = cpu.pc.to_le_bytes();
+        [
+            cpu.a,
+            cpu.x,
+            cpu.y,
+            cpu.flags.to_byte(),
+            cpu.s,
+            pc_low,
+            pc_high,
+        ]
+    }
+
+    fn read_registers(&self) -> String {
+        encode_hex(&self.registers())
+    }
+
+    fn write_registers(&mut self, hex: &str) -> Option<()> {
+        let bytes = decode_hex(hex)?;
+        if bytes.len() != 7 {
+            return None;
+        }
+        for register in 0..5 {
+            self.write_register(register, &bytes[register..=register])?;
+        }
+        self.write_register(5, &bytes[5..])
+    }
+
+    fn read_register(&self, register: usize) -> String {
+        let registers = self.registers();
+        match register {
+            0..=4 => encode_hex(&registers[register..=register]),
+            5 => encode_hex(&registers[5..]),
+            _ => error(),
+        }
+    }
+
+    /// `value` is little endian, like in the `g` packet.
+    fn write_register(&mut self, register: usize, value: &[u8]) -> Option<()> {
+        let cpu = &mut self.nes.cpu;
+        match (register, value) {
+            (0, [a]) => cpu.a = *a,
+            (1, [x]) => cpu.x = *x,
+            (2, [y]) => cpu.y = *y,
+            (3, [p]) => cpu.flags = CPUFlags::from_byte(*p),
+            (4, [s]) => cpu.s = *s,
+            (5, [low, high]) => cpu.pc = u16::from_le_bytes([*low, *high]),
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// `addr,length`, read without side effects on the registers mapped there.
+    /// Like GDB allows, fewer bytes are sent when they wouldn't fit in a packet.
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = args.split_once(',')?;
+        let address = parse_hex(address)? as u16;
+        // Each byte is sent as 2 hex digits
+        let length = (parse_hex(length)? as usize).min(PACKET_SIZE / 2);
+        let bytes: Vec<u8> = (0..length)
+            .map(|i| self.nes.cpu.peek(address.wrapping_add(i as u16)))
+            .collect();
+        Some(encode_hex(&bytes))
+    }
+
+    /// `addr,length:bytes`, written through the bus like the CPU does.
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = range.split_once(',')?;
+        let address = parse_hex(address)? as u16;
+        let bytes = decode_hex(data)?;
+        if bytes.len() != parse_hex(length)? as usize {
+            return None;
+        }
+        for (i, byte) in bytes.into_iter().enumerate() {
+            self.nes.cpu.bus.write(address.wrapping_add(i as u16), byte);
+        }
+        Some(())
+    }
+
+    /// `type,addr,kind`, types 0 and 1 are breakpoints, 2 to 4 write, read and access
+    /// watchpoints. The kind of watchpoints is the length of the range, up to the whole
+    /// 64 KiB address space.
+    fn breakpoint_args(args: &str) -> Option<(u8, u16, u32)> {
+        let mut fields = args.split(';').next()?.split(',');
+        let kind = parse_hex(fields.next()?)? as u8;
+        let address = parse_hex(fields.next()?)?;
+        let length = parse_hex(fields.next()?)?.max(1);
+        if address > 0xFFFF || length > 0x10000 {
+            return None;
+        }
+        Some((kind, address as u16, length))
+    }
+
+    fn insert_breakpoint(&mut self, args: &str) -> Option<()> {
+        let key = Self::breakpoint_args(args)?;
+        let (kind, address, length) = key;
+        // Ranges going past $FFFF stop there
+        let end = (address as u32 + length - 1).min(0xFFFF) as u16;
+        let breakpoint = match kind {
+            0 | 1 => Breakpoint::execute(address),
+            2 => Breakpoint::new(BreakpointKind::Write, address..=end),
+            3 => Breakpoint::new(BreakpointKind::Read, address..=end),
+            4 => Breakpoint::new(BreakpointKind::ReadWrite, address..=end),
+            _ => return None,
+        };
+        if !self.breakpoints.contains_key(&key) {
+            let id = self.nes.cpu.breakpoints.add(breakpoint);
+            self.breakpoints.insert(key, id);
+        }
+        Some(())
+    }
+
+    fn remove_breakpoint(&mut self, args: &str) -> Option<()> {
+        let id = self.breakpoints.remove(&Self::breakpoint_args(args)?)?;
+        self.nes.cpu.breakpoints.remove(id);
+        Some(())
+    }
+
+    /// The breakpoints set by the client don't outlive the session.
+    fn clear_breakpoints(&mut self) {
+        for (_, id) in self.breakpoints.drain() {
+            self.nes.cpu.breakpoints.remove(id);
+        }
+    }
+
+    /// Reads what the client sent, without waiting. True if it sent a Ctrl-C.
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0; 256];
+        let result = loop {
+            match self.stream.read(&mut buffer) {
+                // Nothing would ever interrupt the CPU
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "the client disconnected",
+                    ))
+                }
+                Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result?;
+
+        match self.input.iter().position(|&byte| byte == 0x03) {
+            Some(index) => {
+                self.input.remove(index);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if self.input.is_empty() {
+            let mut buffer = [0; 1024];
+            let n = self.stream.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.input.extend_from_slice(&buffer[..n]);
+        }
+        Ok(Some(self.input.remove(0)))
+    }
+
+    /// Waits for the next packet and returns its data. None once the client disconnects.
+    fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(b'-') => {
+                    let packet = self.last_packet.clone();
+                    self.stream.write_all(&packet)?;
+                    continue;
+                }
+                // Acks, and Ctrl-C while stopped which has nothing to interrupt
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            // The checksum is the one of the bytes as sent, escapes included
+            let mut raw_sum = 0u8;
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    // Escaped byte
+                    Some(b'}') => match self.read_byte()? {
+                        None => return Ok(None),
+                        Some(byte) => {
+                            raw_sum = raw_sum.wrapping_add(b'}').wrapping_add(byte);
+                            data.push(byte ^ 0x20);
+                        }
+                    },
+                    Some(byte) => {
+                        raw_sum = raw_sum.wrapping_add(byte);
+                        data.push(byte);
+                    }
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in checksum.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *digit = byte,
+                }
+            }
+
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(raw_sum);
+            if valid {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut packet = vec![b'$'];
+        for &byte in data.as_bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                packet.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let checksum = sum(&packet[1..]);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.stream.write_all(&packet)?;
+        self.last_packet = packet;
+        Ok(())
+    }
+}
+
+fn ok(result: Option<()>) -> String {
+    match result {
+        Some(()) => "OK".to_string(),
+        None => error(),
+    }
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,255 @@
+use crate::cartridge::{CartridgeError, Mirroring};
+use crate::util::{BitOperations, Units};
+
+/// Which revision of the iNES format a header follows.
+/// See https://wiki.nesdev.com/w/index.php/NES_2.0#Identification
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HeaderFormat {
+    /// Bytes 7-15 may contain garbage (e.g. "DiskDude!") and must be ignored.
+    ArchaicINes,
+    INes,
+    Nes2,
+    /// Converted from a UNIF file, which has no header
+    Unif,
+}
+
+/// CPU/PPU timing of the console the ROM was made for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    /// Works on both NTSC and PAL consoles
+    Multi,
+    Dendy,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem {
+        ppu_type: u8,
+        hardware_type: u8,
+    },
+    PlayChoice10,
+    /// Extended console type (byte 13), e.g. 3 for a Famiclone with decimal mode
+    Extended(u8),
+}
+
+/// The 16 byte header of an iNES or NES 2.0 file.
+/// See https://wiki.nesdev.com/w/index.php/NES_2.0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mapper: u16,
+    pub submapper: u8,
+    /// Hardwired nametable arrangement
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    pub has_trainer: bool,
+
+    // RAM sizes are shift counts, the size is 64 << shift bytes or 0 if the shift is 0.
+    // Only NES 2.0 headers specify them.
+    pub prg_ram_shift: u8,
+    pub prg_nvram_shift: u8,
+    pub chr_ram_shift: u8,
+    pub chr_nvram_shift: u8,
+    /// iNES byte 8, PRG RAM size in 8 KiB units
+    pub ines_prg_ram_units: u8,
+
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_rom_count: u8,
+    pub default_expansion_device: u8,
+}
+
+impl RomHeader {
+    pub const SIZE: usize = 16;
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        if bytes.len() < Self::SIZE {
+            if b"NES\x1A".starts_with(&bytes[..bytes.len().min(4)]) {
+                return Err(CartridgeError::TruncatedHeader);
+            }
+            return Err(CartridgeError::BadMagic);
+        }
+        let header = &bytes[..Self::SIZE];
+
+        if !header.starts_with(b"NES\x1A") {
+            return Err(CartridgeError::BadMagic);
+        }
+
+        let format = match header[7].get_bits(2..=3) {
+            0b10 => HeaderFormat::Nes2,
+            0b00 if header[12..16].iter().all(|&byte| byte == 0) => HeaderFormat::INes,
+            _ => HeaderFormat::ArchaicINes,
+        };
+        let is_nes2 = format == HeaderFormat::Nes2;
+        // Fields that only exist in NES 2.0 headers
+        let nes2_field = |byte: usize, bits| {
+            if is_nes2 {
+                header[byte].get_bits(bits)
+            } else {
+                0
+            }
+        };
+
+        let prg_rom_size = if is_nes2 {
+            nes2_rom_size(header[4], header[9].get_bits(0..=3), 16.KiB()).ok_or(
+                CartridgeError::InvalidNes2Field {
+                    field: "PRG ROM size",
+                    value: header[4],
+                },
+            )?
+        } else {
+            header[4] as usize * 16.KiB()
+        };
+
+        if prg_rom_size == 0 {
+            return Err(CartridgeError::MissingPrgRom);
+        }
+
+        let chr_rom_size = if is_nes2 {
+            nes2_rom_size(header[5], header[9].get_bits(4..=7), 8.KiB()).ok_or(
+                CartridgeError::InvalidNes2Field {
+                    field: "CHR ROM size",
+                    value: header[5],
+                },
+            )?
+        } else {
+            header[5] as usize * 8.KiB()
+        };
+
+        let mapper = {
+            let mut n = 0u16;
+            n.set_bits(0..=3, header[6].get_bits(4..=7) as u16);
+            if format != HeaderFormat::ArchaicINes {
+                n.set_bits(4..=7, header[7].get_bits(4..=7) as u16);
+            }
+            if is_nes2 {
+                n.set_bits(8..=11, header[8].get_bits(0..=3) as u16);
+            }
+            n
+        };
+
+        let mirroring = if header[6].get_bit(3) {
+            Mirroring::FourScreen
+        } else if header[6].get_bit(0) {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let console_type = if format == HeaderFormat::ArchaicINes {
+            ConsoleType::Nes
+        } else {
+            match header[7].get_bits(0..=1) {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem {
+                    ppu_type: nes2_field(13, 0..=3),
+                    hardware_type: nes2_field(13, 4..=7),
+                },
+                2 => ConsoleType::PlayChoice10,
+                // Only NES 2.0 has extended console types, iNES used both bits as flags
+                _ if is_nes2 => ConsoleType::Extended(nes2_field(13, 0..=3)),
+                _ => ConsoleType::Nes,
+            }
+        };
+
+        let timing = if is_nes2 {
+            match header[12].get_bits(0..=1) {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::Multi,
+                _ => Timing::Dendy,
+            }
+        } else if format == HeaderFormat::INes && header[9].get_bit(0) {
+            Timing::Pal
+        } else {
+            Timing::Ntsc
+        };
+
+        Ok(Self {
+            format,
+            prg_rom_size,
+            chr_rom_size,
+            mapper,
+            submapper: nes2_field(8, 4..=7),
+            mirroring,
+            has_battery: header[6].get_bit(1),
+            has_trainer: header[6].get_bit(2),
+            prg_ram_shift: nes2_field(10, 0..=3),
+            prg_nvram_shift: nes2_field(10, 4..=7),
+            chr_ram_shift: nes2_field(11, 0..=3),
+            chr_nvram_shift: nes2_field(11, 4..=7),
+            ines_prg_ram_units: if format == HeaderFormat::INes {
+                header[8]
+            } else {
+                0
+            },
+            timing,
+            console_type,
+            misc_rom_count: nes2_field(14, 0..=1),
+            default_expansion_device: nes2_field(15, 0..=5),
+        })
+    }
+
+    /// Volatile PRG RAM at $6000-$7FFF.
+    pub fn prg_ram_size(&self) -> usize {
+        match self.format {
+            HeaderFormat::Nes2 => shift_to_size(self.prg_ram_shift),
+            // 0 infers 8 KiB for compatibility, the battery decides whether it's persisted
+            _ if self.has_battery => 0,
+            _ => self.ines_prg_ram_units.max(1) as usize * 8.KiB(),
+        }
+    }
+
+    /// Battery backed PRG RAM at $6000-$7FFF.
+    pub fn prg_nvram_size(&self) -> usize {
+        match self.format {
+            HeaderFormat::Nes2 => shift_to_size(self.prg_nvram_shift),
+            _ if self.has_battery => self.ines_prg_ram_units.max(1) as usize * 8.KiB(),
+            _ => 0,
+        }
+    }
+
+    pub fn chr_ram_size(&self) -> usize {
+        match self.format {
+            HeaderFormat::Nes2 => shift_to_size(self.chr_ram_shift),
+            // iNES implies 8 KiB of CHR RAM when there is no CHR ROM
+            _ if self.chr_rom_size == 0 => 8.KiB(),
+            _ => 0,
+        }
+    }
+
+    pub fn chr_nvram_size(&self) -> usize {
+        match self.format {
+            HeaderFormat::Nes2 => shift_to_size(self.chr_nvram_shift),
+            _ => 0,
+        }
+    }
+}
+
+fn shift_to_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+/// NES 2.0 ROM sizes are either a 12 bit count of `unit` sized banks or,
+/// when the MSB nibble is $F, in exponent-multiplier notation: 2^E * (MM * 2 + 1) bytes.
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    if msb == 0xF {
+        let multiplier = lsb.get_bits(0..=1) as usize * 2 + 1;
+        let exponent = lsb.get_bits(2..=7) as u32;
+        2usize.checked_pow(exponent)?.checked_mul(multiplier)
+    } else {
+        let mut size = 0u16;
+        size.set_bits(0..=7, lsb as u16);
+        size.set_bits(8..=11, msb as u16);
+        Some(size as usize * unit)
+    }
+}
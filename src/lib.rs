@@ -0,0 +1,22 @@
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod archive;
+pub mod breakpoints;
+pub mod bus;
+pub mod cartridge;
+pub mod checksum;
+pub mod cpu;
+pub mod database;
+pub mod debugger;
+pub mod disassembler;
+pub mod flags;
+pub mod gdb;
+pub mod header;
+pub mod mapper;
+pub mod nes;
+pub mod opcodes;
+pub mod palette;
+pub mod ppu;
+mod tests;
+pub mod unif;
+pub mod util;
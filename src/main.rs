@@ -1,21 +1,23 @@
-use crate::cartridge::Cartridge;
-use crate::cpu::CPU;
+use nesmulator::bus::Bus;
+use nesmulator::cartridge::{Cartridge, CartridgeError};
+use nesmulator::nes::{Nes, StopReason};
 
-mod cartridge;
-mod cpu;
-mod disassembler;
-mod flags;
-mod mapper;
-mod opcodes;
-mod tests;
-mod util;
+fn main() -> Result<(), CartridgeError> {
+    let cartridge = Cartridge::from_file("misc/nestest.nes")?;
+    let mut nes = Nes::new(cartridge);
+    // The automated mode of nestest starts at $C000 instead of the reset vector
+    nes.cpu.pc = 0xc000;
 
-fn main() {
-    let cartridge = Cartridge::from_file("misc/nestest.nes");
-    let mut cpu = CPU::new(cartridge);
-    cpu.pc = 0xc000;
-
-    while cpu.pc != 0xC6BD {
-        cpu.clock();
+    // The last instruction of the automated tests
+    if let StopReason::Jammed { pc, opcode } = nes.run_until(|nes| nes.cpu.pc == 0xC66E) {
+        eprintln!("CPU jammed by opcode ${:02X} at ${:04X}", opcode, pc);
     }
+    println!(
+        "nestest: official ${:02X}, unofficial ${:02X}",
+        nes.cpu.bus.read(0x0002),
+        nes.cpu.bus.read(0x0003)
+    );
+
+    nes.save()?;
+    Ok(())
 }
@@ -1,9 +1,94 @@
 use std::fmt::Debug;
 
+use crate::cartridge::Mirroring;
 use crate::util::Units;
 
+pub use self::mapper1::Mapper1;
+pub use self::mapper2::Mapper2;
+pub use self::mapper3::Mapper3;
+pub use self::mapper4::{Mapper4, Mmc3Revision};
+pub use self::mapper7::Mapper7;
+
+mod mapper1;
+mod mapper2;
+mod mapper3;
+mod mapper4;
+mod mapper7;
+
 pub trait Mapper: Debug {
-    fn address(&self, address: u16) -> u16;
+    /// Maps a CPU address ($8000-$FFFF) to an offset in PRG ROM.
+    fn prg_address(&self, address: u16) -> usize;
+
+    /// Maps a CPU address ($6000-$7FFF) to an offset in PRG RAM, `None` if disabled.
+    fn prg_ram_address(&self, address: u16) -> Option<usize> {
+        Some(address as usize & 0x1FFF)
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        true
+    }
+
+    /// Maps a PPU address ($0000-$1FFF) to an offset in CHR ROM or CHR RAM.
+    fn chr_address(&self, address: u16) -> usize;
+
+    /// CPU writes to $8000-$FFFF, usually bank switching registers.
+    fn write(&mut self, _address: u16, _value: u8) {}
+
+    /// On boards without a way to disable the ROM during writes, both the ROM and the CPU
+    /// drive the data bus and the mapper sees the AND of the two values.
+    fn has_bus_conflicts(&self) -> bool {
+        false
+    }
+
+    /// Overrides the mirroring of the header, for mappers that control it.
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    /// Called once per CPU cycle (M2).
+    fn cpu_clock(&mut self) {}
+
+    /// Called with every address the PPU puts on its bus.
+    fn ppu_address(&mut self, _address: u16) {}
+
+    /// State of the IRQ line of the cartridge.
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+/// Common name of an iNES mapper number, for error messages and tools.
+pub fn mapper_name(number: u16) -> &'static str {
+    match number {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        5 => "MMC5",
+        7 => "AxROM",
+        9 => "MMC2",
+        10 => "MMC4",
+        11 => "Color Dreams",
+        13 => "CPROM",
+        16 => "Bandai FCG",
+        18 => "Jaleco SS88006",
+        19 => "Namco 163",
+        21 | 23 | 25 => "VRC2/VRC4",
+        22 => "VRC2a",
+        24 | 26 => "VRC6",
+        34 => "BNROM/NINA-001",
+        64 => "RAMBO-1",
+        66 => "GxROM",
+        69 => "Sunsoft FME-7",
+        71 => "Camerica/Codemasters",
+        73 => "VRC3",
+        75 => "VRC1",
+        79 => "NINA-03/NINA-06",
+        85 => "VRC7",
+        206 => "DxROM",
+        _ => "unknown",
+    }
 }
 
 #[derive(Debug)]
@@ -18,18 +103,12 @@ impl Mapper0 {
 }
 
 impl Mapper for Mapper0 {
-    fn address(&self, address: u16) -> u16 {
-        assert!(address > 0x4020);
-        if address < 0x8000 {
-            unimplemented!("What is this address ({:x}) ??", address);
-        }
-
-        let address = address - 0x8000;
-
-        if self.prg_rom_size == 16.KiB() {
-            address % 16.KiB()
-        } else {
-            address
-        }
+    fn prg_address(&self, address: u16) -> usize {
+        // 16 KiB, or less, is mirrored
+        (address - 0x8000) as usize % self.prg_rom_size.clamp(1, 32.KiB())
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        address as usize & 0x1FFF
     }
 }
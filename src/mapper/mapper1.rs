@@ -0,0 +1,175 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::util::{BitOperations, Units};
+
+/// MMC1 (SxROM boards).
+/// Registers are written one bit at a time through a 5 bit shift register.
+/// See https://wiki.nesdev.com/w/index.php/MMC1
+#[derive(Debug)]
+pub struct Mapper1 {
+    prg_rom_size: usize,
+    chr_size: usize,
+    prg_ram_size: usize,
+
+    shift_register: u8,
+    writes: u8,
+    /// The serial port ignores a write on the cycle right after another one,
+    /// e.g. the second write of read-modify-write instructions
+    wrote_this_cycle: bool,
+    wrote_last_cycle: bool,
+
+    /// $8000-$9FFF
+    control: u8,
+    /// $A000-$BFFF
+    chr_bank_0: u8,
+    /// $C000-$DFFF
+    chr_bank_1: u8,
+    /// $E000-$FFFF
+    prg_bank: u8,
+}
+
+impl Mapper1 {
+    pub fn new(prg_rom_size: usize, chr_size: usize, prg_ram_size: usize) -> Self {
+        Self {
+            prg_rom_size,
+            chr_size,
+            prg_ram_size,
+            shift_register: 0,
+            writes: 0,
+            wrote_this_cycle: false,
+            wrote_last_cycle: false,
+            // PRG mode 3 at power on, the last bank is fixed at $C000
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    /// SUROM and SXROM use bit 4 of the CHR bank to select a 256 KiB PRG ROM half.
+    fn prg_outer_bank(&self) -> usize {
+        if self.prg_rom_size > 256.KiB() {
+            self.chr_bank_0.get_bit(4) as usize * 256.KiB()
+        } else {
+            0
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            0xE000..=0xFFFF => self.prg_bank = value,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Mapper for Mapper1 {
+    fn prg_address(&self, address: u16) -> usize {
+        let offset = address as usize & 0x3FFF;
+        let bank = self.prg_bank.get_bits(0..=3) as usize;
+        // Banks count in 16 KiB inside the selected 256 KiB half
+        let bank_count = (self.prg_rom_size.min(256.KiB()) / 16.KiB()).max(1);
+        let last_bank = bank_count - 1;
+
+        let bank = match self.control.get_bits(2..=3) {
+            // 32 KiB mode, the low bit of the bank number is ignored
+            0 | 1 => (bank & !1) | (address >= 0xC000) as usize,
+            // First bank fixed at $8000, switchable bank at $C000
+            2 => {
+                if address < 0xC000 {
+                    0
+                } else {
+                    bank
+                }
+            }
+            // Switchable bank at $8000, last bank fixed at $C000
+            _ => {
+                if address < 0xC000 {
+                    bank
+                } else {
+                    last_bank
+                }
+            }
+        };
+
+        self.prg_outer_bank() + (bank % bank_count) * 16.KiB() + offset
+    }
+
+    fn prg_ram_address(&self, address: u16) -> Option<usize> {
+        // MMC1B and later can disable PRG RAM
+        if self.prg_bank.get_bit(4) {
+            return None;
+        }
+        let bank = match self.prg_ram_size {
+            // SOROM
+            0x4000 => self.chr_bank_0.get_bit(3) as usize,
+            // SXROM
+            0x8000 => self.chr_bank_0.get_bits(2..=3) as usize,
+            _ => 0,
+        };
+        Some(bank * 8.KiB() + (address as usize & 0x1FFF))
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let address = address as usize & 0x1FFF;
+        let bank_count = (self.chr_size / 4.KiB()).max(1);
+
+        let bank = if self.control.get_bit(4) {
+            // Two 4 KiB banks
+            if address < 0x1000 {
+                self.chr_bank_0 as usize
+            } else {
+                self.chr_bank_1 as usize
+            }
+        } else {
+            // One 8 KiB bank, the low bit is ignored
+            (self.chr_bank_0 as usize & !1) | (address >= 0x1000) as usize
+        };
+
+        (bank % bank_count) * 4.KiB() + (address & 0x0FFF)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let consecutive = self.wrote_last_cycle;
+        self.wrote_this_cycle = true;
+        if consecutive {
+            return;
+        }
+
+        if value.get_bit(7) {
+            self.shift_register = 0;
+            self.writes = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        // Bits come in LSB first
+        self.shift_register >>= 1;
+        self.shift_register.set_bit(4, value.get_bit(0));
+        self.writes += 1;
+
+        if self.writes == 5 {
+            // Only the address of the fifth write selects the register
+            self.write_register(address, self.shift_register);
+            self.shift_register = 0;
+            self.writes = 0;
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.wrote_last_cycle = self.wrote_this_cycle;
+        self.wrote_this_cycle = false;
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control.get_bits(0..=1) {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+}
@@ -0,0 +1,44 @@
+use crate::mapper::Mapper;
+use crate::util::Units;
+
+/// UxROM, a switchable 16 KiB bank at $8000 and the last bank fixed at $C000.
+#[derive(Debug)]
+pub struct Mapper2 {
+    prg_rom_size: usize,
+    bus_conflicts: bool,
+    prg_bank: u8,
+}
+
+impl Mapper2 {
+    pub fn new(prg_rom_size: usize, bus_conflicts: bool) -> Self {
+        Self {
+            prg_rom_size,
+            bus_conflicts,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper2 {
+    fn prg_address(&self, address: u16) -> usize {
+        let bank_count = (self.prg_rom_size / 16.KiB()).max(1);
+        let bank = if address < 0xC000 {
+            self.prg_bank as usize % bank_count
+        } else {
+            bank_count - 1
+        };
+        bank * 16.KiB() + (address as usize & 0x3FFF)
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        address as usize & 0x1FFF
+    }
+
+    fn write(&mut self, _address: u16, value: u8) {
+        self.prg_bank = value;
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+}
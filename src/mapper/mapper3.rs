@@ -0,0 +1,41 @@
+use crate::mapper::Mapper;
+use crate::util::Units;
+
+/// CNROM, NROM with a switchable 8 KiB CHR bank.
+#[derive(Debug)]
+pub struct Mapper3 {
+    prg_rom_size: usize,
+    chr_size: usize,
+    bus_conflicts: bool,
+    chr_bank: u8,
+}
+
+impl Mapper3 {
+    pub fn new(prg_rom_size: usize, chr_size: usize, bus_conflicts: bool) -> Self {
+        Self {
+            prg_rom_size,
+            chr_size,
+            bus_conflicts,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper3 {
+    fn prg_address(&self, address: u16) -> usize {
+        (address as usize - 0x8000) % self.prg_rom_size.min(32.KiB())
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let bank_count = (self.chr_size / 8.KiB()).max(1);
+        (self.chr_bank as usize % bank_count) * 8.KiB() + (address as usize & 0x1FFF)
+    }
+
+    fn write(&mut self, _address: u16, value: u8) {
+        self.chr_bank = value;
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+}
@@ -0,0 +1,183 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::util::{BitOperations, Units};
+
+/// The MMC3 IRQ counter behaves differently depending on the chip manufacturer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mmc3Revision {
+    /// MMC3B and MMC3C, an IRQ is raised every time the counter is clocked while 0.
+    Sharp,
+    /// MMC3A, an IRQ is only raised when the counter goes from non-zero to 0 (or is reloaded).
+    Nec,
+}
+
+/// MMC3 (TxROM boards).
+/// See https://wiki.nesdev.com/w/index.php/MMC3
+#[derive(Debug)]
+pub struct Mapper4 {
+    prg_rom_size: usize,
+    chr_size: usize,
+    revision: Mmc3Revision,
+
+    /// $8000, bits 0-2: register to update, bit 6: PRG mode, bit 7: CHR inversion
+    bank_select: u8,
+    /// R0-R7, written through $8001
+    banks: [u8; 8],
+    /// $A000
+    mirroring: Mirroring,
+    /// $A001, bit 6: write protection, bit 7: enable
+    prg_ram_protect: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    /// PPU A12 has to stay low for a few CPU cycles before a rising edge clocks the counter
+    a12: bool,
+    a12_low_cycles: u8,
+}
+
+impl Mapper4 {
+    pub fn new(prg_rom_size: usize, chr_size: usize, revision: Mmc3Revision) -> Self {
+        Self {
+            prg_rom_size,
+            chr_size,
+            revision,
+            bank_select: 0,
+            banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_cycles: 0,
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let old_counter = self.irq_counter;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        let should_trigger = match self.revision {
+            Mmc3Revision::Sharp => self.irq_counter == 0,
+            Mmc3Revision::Nec => self.irq_counter == 0 && (old_counter != 0 || self.irq_reload),
+        };
+        if should_trigger && self.irq_enabled {
+            self.irq_pending = true;
+        }
+        self.irq_reload = false;
+    }
+}
+
+impl Mapper for Mapper4 {
+    fn prg_address(&self, address: u16) -> usize {
+        let bank_count = (self.prg_rom_size / 8.KiB()).max(1);
+        let second_last = bank_count.saturating_sub(2);
+        let is_swapped = self.bank_select.get_bit(6);
+
+        let bank = match (address, is_swapped) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.banks[6] as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => second_last,
+            (0xA000..=0xBFFF, _) => self.banks[7] as usize,
+            _ => bank_count - 1,
+        };
+
+        (bank % bank_count) * 8.KiB() + (address as usize & 0x1FFF)
+    }
+
+    fn prg_ram_address(&self, address: u16) -> Option<usize> {
+        if self.prg_ram_protect.get_bit(7) {
+            Some(address as usize & 0x1FFF)
+        } else {
+            None
+        }
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        !self.prg_ram_protect.get_bit(6)
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let address = address as usize & 0x1FFF;
+        // CHR inversion swaps the 2 KiB banks and the 1 KiB banks
+        let slot = if self.bank_select.get_bit(7) {
+            address ^ 0x1000
+        } else {
+            address
+        } / 1.KiB();
+
+        let bank = match slot {
+            0 => self.banks[0] & 0xFE,
+            1 => self.banks[0] | 0x01,
+            2 => self.banks[1] & 0xFE,
+            3 => self.banks[1] | 0x01,
+            _ => self.banks[slot - 2],
+        } as usize;
+
+        let bank_count = (self.chr_size / 1.KiB()).max(1);
+        (bank % bank_count) * 1.KiB() + (address & 0x03FF)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let is_even = !address.get_bit(0);
+        match (address, is_even) {
+            (0x8000..=0x9FFF, true) => self.bank_select = value,
+            (0x8000..=0x9FFF, false) => {
+                self.banks[self.bank_select.get_bits(0..=2) as usize] = value;
+            }
+            (0xA000..=0xBFFF, true) => {
+                self.mirroring = if value.get_bit(0) {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+            }
+            (0xA000..=0xBFFF, false) => self.prg_ram_protect = value,
+            (0xC000..=0xDFFF, true) => self.irq_latch = value,
+            (0xC000..=0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000..=0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xE000..=0xFFFF, false) => self.irq_enabled = true,
+            _ => unreachable!(),
+        }
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn ppu_address(&mut self, address: u16) {
+        let a12 = address.get_bit(12);
+        if a12 && !self.a12 && self.a12_low_cycles >= 3 {
+            self.clock_irq_counter();
+        }
+        if !a12 && self.a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+}
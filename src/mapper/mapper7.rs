@@ -0,0 +1,50 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::util::{BitOperations, Units};
+
+/// AxROM, a switchable 32 KiB PRG bank and single-screen mirroring selection.
+#[derive(Debug)]
+pub struct Mapper7 {
+    prg_rom_size: usize,
+    bus_conflicts: bool,
+    /// Bits 0-2: PRG bank, bit 4: nametable page
+    register: u8,
+}
+
+impl Mapper7 {
+    pub fn new(prg_rom_size: usize, bus_conflicts: bool) -> Self {
+        Self {
+            prg_rom_size,
+            bus_conflicts,
+            register: 0,
+        }
+    }
+}
+
+impl Mapper for Mapper7 {
+    fn prg_address(&self, address: u16) -> usize {
+        let bank_count = (self.prg_rom_size / 32.KiB()).max(1);
+        let bank = self.register.get_bits(0..=2) as usize % bank_count;
+        bank * 32.KiB() + (address as usize & 0x7FFF)
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        address as usize & 0x1FFF
+    }
+
+    fn write(&mut self, _address: u16, value: u8) {
+        self.register = value;
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        if self.register.get_bit(4) {
+            Some(Mirroring::SingleScreenB)
+        } else {
+            Some(Mirroring::SingleScreenA)
+        }
+    }
+}
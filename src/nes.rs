@@ -0,0 +1,126 @@
+use std::io;
+
+use crate::breakpoints::BreakpointHit;
+use crate::bus::{RamInit, SystemBus};
+use crate::cartridge::Cartridge;
+use crate::cpu::{CpuState, CPU};
+
+/// The CPU divides the 21.477272 MHz NTSC master clock by 12, the PPU by 4.
+pub const MASTER_CYCLES_PER_CPU_CYCLE: u64 = 12;
+
+/// Why a `step_*` or `run_*` call returned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// The instruction, cycles or frame asked for ran to completion
+    Done,
+    /// The condition of `run_until` became true
+    Condition,
+    /// A JAM opcode locked up the CPU
+    Jammed { pc: u16, opcode: u8 },
+    /// A BRK ran while `break_on_brk` is set. Instruction steps stop once it has run,
+    /// cycle steps right after its opcode fetch.
+    Break { pc: u16 },
+    /// A breakpoint of `cpu.breakpoints` was hit. Instruction steps stop before the
+    /// instruction of an execute breakpoint and after the one that hit a watchpoint.
+    Breakpoint(BreakpointHit),
+}
+
+/// The whole console, the entry point to run the emulation.
+#[derive(Debug)]
+pub struct Nes {
+    pub cpu: CPU<SystemBus>,
+}
+
+impl Nes {
+    pub fn new(cartridge: Cartridge) -> Self {
+        Self::with_ram_init(cartridge, RamInit::default())
+    }
+
+    pub fn with_ram_init(cartridge: Cartridge, ram_init: RamInit) -> Self {
+        Self {
+            cpu: CPU::new(SystemBus::with_ram_init(cartridge, ram_init)),
+        }
+    }
+
+    pub fn power_on(&mut self) {
+        self.cpu.power_on();
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    /// Master clock cycles since power on.
+    pub fn master_cycles(&self) -> u64 {
+        self.cpu.cycles * MASTER_CYCLES_PER_CPU_CYCLE
+    }
+
+    /// CPU cycles since power on.
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu.cycles
+    }
+
+    /// Runs a single CPU cycle, the PPU runs 3 dots meanwhile.
+    pub fn step_cycle(&mut self) -> StopReason {
+        stop_reason(self.cpu.clock())
+    }
+
+    /// Runs until the end of the current instruction, or of the next one
+    /// if the CPU is between two instructions.
+    pub fn step_instruction(&mut self) -> StopReason {
+        stop_reason(self.cpu.step())
+    }
+
+    /// Runs until the PPU has drawn a whole frame and entered VBlank.
+    pub fn run_frame(&mut self) -> StopReason {
+        // Only the frames completed from now count
+        self.cpu.bus.ppu.poll_frame_complete();
+        loop {
+            let reason = self.step_cycle();
+            if reason != StopReason::Done || self.cpu.bus.ppu.poll_frame_complete() {
+                return reason;
+            }
+        }
+    }
+
+    /// Runs `cycles` CPU cycles, possibly stopping in the middle of an instruction.
+    pub fn run_for_cycles(&mut self, cycles: u64) -> StopReason {
+        for _ in 0..cycles {
+            let reason = self.step_cycle();
+            if reason != StopReason::Done {
+                return reason;
+            }
+        }
+        StopReason::Done
+    }
+
+    /// Runs instructions until `condition` is true, it is checked after each of them.
+    pub fn run_until<F>(&mut self, mut condition: F) -> StopReason
+    where
+        F: FnMut(&Nes) -> bool,
+    {
+        loop {
+            let reason = self.step_instruction();
+            if reason != StopReason::Done {
+                return reason;
+            }
+            if condition(self) {
+                return StopReason::Condition;
+            }
+        }
+    }
+
+    /// Writes the battery backed PRG RAM next to the ROM, frontends call it before exiting.
+    pub fn save(&self) -> io::Result<()> {
+        self.cpu.bus.cartridge.save()
+    }
+}
+
+fn stop_reason(state: CpuState) -> StopReason {
+    match state {
+        CpuState::Running => StopReason::Done,
+        CpuState::Jammed { pc, opcode } => StopReason::Jammed { pc, opcode },
+        CpuState::Break { pc } => StopReason::Break { pc },
+        CpuState::Breakpoint(hit) => StopReason::Breakpoint(hit),
+    }
+}
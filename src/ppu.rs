@@ -0,0 +1,649 @@
+use crate::cartridge::Cartridge;
+use crate::palette::PALETTE;
+use crate::util::BitOperations;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+pub const DOTS_PER_SCANLINE: u16 = 341;
+pub const SCANLINES_PER_FRAME: u16 = 262;
+pub const VBLANK_SCANLINE: u16 = 241;
+pub const PRE_RENDER_SCANLINE: u16 = 261;
+
+/// One of the 8 sprites selected for the next scanline.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Sprite {
+    pub index: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    pub x: u8,
+    /// Already flipped horizontally if needed, bit 7 is the leftmost pixel
+    pub pattern_lsb: u8,
+    pub pattern_msb: u8,
+}
+
+/// The 2C02.
+#[derive(Debug)]
+pub struct PPU {
+    /// $2000
+    pub ctrl: u8,
+    /// $2001
+    pub mask: u8,
+    /// $2002, only the 3 upper bits are meaningful
+    pub status: u8,
+    /// $2003
+    pub oam_address: u8,
+
+    pub oam: [u8; 0x100],
+    /// Internal 2 KiB of VRAM (CIRAM) holding the nametables
+    pub vram: [u8; 0x800],
+    pub palette: [u8; 0x20],
+
+    // Internal "loopy" registers, see https://wiki.nesdev.com/w/index.php/PPU_scrolling
+    /// Current VRAM address (15 bits)
+    pub v: u16,
+    /// Temporary VRAM address (15 bits), the address of the top left tile of the screen
+    pub t: u16,
+    /// Fine X scroll (3 bits)
+    pub x: u8,
+    /// First or second write toggle of $2005 and $2006
+    pub w: bool,
+
+    /// $2007 reads are delayed by one read
+    pub read_buffer: u8,
+    /// Value left on the PPU data bus by the last register access
+    pub io_latch: u8,
+
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame: u64,
+
+    // Background pipeline
+    bg_next_tile_id: u8,
+    bg_next_tile_attribute: u8,
+    bg_next_tile_lsb: u8,
+    bg_next_tile_msb: u8,
+    bg_shifter_pattern_lsb: u16,
+    bg_shifter_pattern_msb: u16,
+    bg_shifter_attribute_lsb: u16,
+    bg_shifter_attribute_msb: u16,
+
+    // Sprite pipeline
+    sprites: [Sprite; 8],
+    sprite_count: usize,
+
+    /// Palette indices of the frame being drawn
+    frame_buffer: Box<[u8; SCREEN_WIDTH * SCREEN_HEIGHT]>,
+
+    nmi_pending: bool,
+    frame_complete: bool,
+}
+
+impl PPU {
+    pub fn new() -> Self {
+        Self {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_address: 0,
+            oam: [0; 0x100],
+            vram: [0; 0x800],
+            palette: [0; 0x20],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            bg_next_tile_id: 0,
+            bg_next_tile_attribute: 0,
+            bg_next_tile_lsb: 0,
+            bg_next_tile_msb: 0,
+            bg_shifter_pattern_lsb: 0,
+            bg_shifter_pattern_msb: 0,
+            bg_shifter_attribute_lsb: 0,
+            bg_shifter_attribute_msb: 0,
+            sprites: [Sprite::default(); 8],
+            sprite_count: 0,
+            frame_buffer: Box::new([0; SCREEN_WIDTH * SCREEN_HEIGHT]),
+            nmi_pending: false,
+            frame_complete: false,
+        }
+    }
+
+    pub fn clock(&mut self, cartridge: &mut Cartridge) {
+        let is_visible_scanline = self.scanline < SCREEN_HEIGHT as u16;
+        let is_pre_render_scanline = self.scanline == PRE_RENDER_SCANLINE;
+
+        if self.is_rendering_enabled() && (is_visible_scanline || is_pre_render_scanline) {
+            self.render_dot(cartridge);
+        }
+        if is_visible_scanline && (1..=SCREEN_WIDTH as u16).contains(&self.dot) {
+            self.output_pixel();
+        }
+
+        if self.scanline == VBLANK_SCANLINE && self.dot == 1 {
+            self.set_vblank(true);
+            self.frame_complete = true;
+        }
+        if self.scanline == PRE_RENDER_SCANLINE && self.dot == 1 {
+            self.set_vblank(false);
+            // Sprite 0 hit and sprite overflow
+            self.status.set_bit(6, false);
+            self.status.set_bit(5, false);
+        }
+
+        // The pre-render scanline is one dot shorter on odd frames when rendering
+        let skip_last_dot = is_pre_render_scanline
+            && self.dot == DOTS_PER_SCANLINE - 2
+            && self.frame % 2 == 1
+            && self.is_rendering_enabled();
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_SCANLINE || skip_last_dot {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+    }
+
+    /// Returns true once per NMI edge.
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi_pending, false)
+    }
+
+    /// Returns true once per frame, when the PPU enters VBlank.
+    pub fn poll_frame_complete(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_complete, false)
+    }
+
+    /// The last rendered frame, one palette index (0-63) per pixel, row by row.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame_buffer[..]
+    }
+
+    /// The last rendered frame as packed 24 bit RGB.
+    pub fn frame_buffer_rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        for &index in self.frame_buffer.iter() {
+            let (r, g, b) = PALETTE[index as usize & 0x3F];
+            rgb.extend_from_slice(&[r, g, b]);
+        }
+        rgb
+    }
+
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.mask.get_bit(3) || self.mask.get_bit(4)
+    }
+
+    fn render_dot(&mut self, cartridge: &mut Cartridge) {
+        match self.dot {
+            2..=257 | 321..=337 => {
+                self.update_shifters();
+                match (self.dot - 1) % 8 {
+                    0 => {
+                        self.load_bg_shifters();
+                        self.bg_next_tile_id =
+                            self.read_memory(0x2000 | (self.v & 0x0FFF), cartridge);
+                    }
+                    2 => {
+                        let address = 0x23C0
+                            | (self.v & 0x0C00)
+                            | ((self.v >> 4) & 0x38)
+                            | ((self.v >> 2) & 0x07);
+                        let mut attribute = self.read_memory(address, cartridge);
+                        // Each attribute byte covers 4x4 tiles, 2 bits per 2x2 quadrant
+                        if self.coarse_y() & 0x02 != 0 {
+                            attribute >>= 4;
+                        }
+                        if self.coarse_x() & 0x02 != 0 {
+                            attribute >>= 2;
+                        }
+                        self.bg_next_tile_attribute = attribute & 0x03;
+                    }
+                    4 => {
+                        let address = self.bg_pattern_address();
+                        self.bg_next_tile_lsb = self.read_memory(address, cartridge);
+                    }
+                    6 => {
+                        let address = self.bg_pattern_address() + 8;
+                        self.bg_next_tile_msb = self.read_memory(address, cartridge);
+                    }
+                    7 => self.increment_coarse_x(),
+                    _ => {}
+                }
+                if self.dot == 256 {
+                    self.increment_y();
+                }
+                if self.dot == 257 {
+                    self.load_bg_shifters();
+                    self.copy_horizontal_scroll();
+                    self.evaluate_sprites();
+                }
+            }
+            338 | 340 => {
+                // Unused nametable fetches
+                self.bg_next_tile_id = self.read_memory(0x2000 | (self.v & 0x0FFF), cartridge);
+            }
+            280..=304 if self.scanline == PRE_RENDER_SCANLINE => self.copy_vertical_scroll(),
+            _ => {}
+        }
+
+        // Sprite pattern fetches for the next scanline
+        if (258..=320).contains(&self.dot) {
+            self.oam_address = 0;
+            let slot = (self.dot - 257) / 8;
+            match (self.dot - 257) % 8 {
+                4 => {
+                    let address = self.sprite_pattern_address(slot as usize);
+                    let lsb = self.read_memory(address, cartridge);
+                    self.set_sprite_pattern(slot as usize, lsb, false);
+                }
+                6 => {
+                    let address = self.sprite_pattern_address(slot as usize) + 8;
+                    let msb = self.read_memory(address, cartridge);
+                    self.set_sprite_pattern(slot as usize, msb, true);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn output_pixel(&mut self) {
+        let x = self.dot as usize - 1;
+        let y = self.scanline as usize;
+
+        let (bg_pixel, bg_palette) = if self.mask.get_bit(3) && (x >= 8 || self.mask.get_bit(1)) {
+            let bit = 15 - self.x;
+            let pixel = (self.bg_shifter_pattern_msb.get_bit(bit) as u8) << 1
+                | self.bg_shifter_pattern_lsb.get_bit(bit) as u8;
+            let palette = (self.bg_shifter_attribute_msb.get_bit(bit) as u8) << 1
+                | self.bg_shifter_attribute_lsb.get_bit(bit) as u8;
+            (pixel, palette)
+        } else {
+            (0, 0)
+        };
+
+        let sprite = if self.mask.get_bit(4) && (x >= 8 || self.mask.get_bit(2)) {
+            self.sprite_pixel(x)
+        } else {
+            None
+        };
+
+        let (pixel, palette) = match (bg_pixel, sprite) {
+            (0, None) => (0, 0),
+            (0, Some((pixel, palette, _, _))) => (pixel, palette),
+            (_, None) => (bg_pixel, bg_palette),
+            (_, Some((pixel, palette, behind_background, is_sprite_zero))) => {
+                if is_sprite_zero && x != 255 {
+                    self.status.set_bit(6, true);
+                }
+                if behind_background {
+                    (bg_pixel, bg_palette)
+                } else {
+                    (pixel, palette)
+                }
+            }
+        };
+
+        let mut color = if pixel == 0 {
+            self.palette[0]
+        } else {
+            self.palette[palette_index(0x3F00 + palette as u16 * 4 + pixel as u16)]
+        };
+        // Greyscale
+        if self.mask.get_bit(0) {
+            color &= 0x30;
+        }
+        self.frame_buffer[y * SCREEN_WIDTH + x] = color & 0x3F;
+    }
+
+    /// Returns (pixel, palette, behind background, is sprite 0) of the first opaque sprite at x.
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, u8, bool, bool)> {
+        self.sprites[..self.sprite_count].iter().find_map(|sprite| {
+            let offset = x
+                .checked_sub(sprite.x as usize)
+                .filter(|&offset| offset < 8)?;
+            let bit = 7 - offset as u8;
+            let pixel = (sprite.pattern_msb.get_bit(bit) as u8) << 1
+                | sprite.pattern_lsb.get_bit(bit) as u8;
+            if pixel == 0 {
+                return None;
+            }
+            Some((
+                pixel,
+                sprite.attributes.get_bits(0..=1) + 4,
+                sprite.attributes.get_bit(5),
+                sprite.index == 0,
+            ))
+        })
+    }
+
+    /// Selects the sprites of the next scanline, including the sprite overflow hardware bug.
+    fn evaluate_sprites(&mut self) {
+        self.sprite_count = 0;
+        if self.scanline == PRE_RENDER_SCANLINE {
+            return;
+        }
+
+        let scanline = self.scanline as i16;
+        let height = self.sprite_height() as i16;
+        let is_in_range = |y: u8| (0..height).contains(&(scanline - y as i16));
+
+        let mut n = 0;
+        while n < 64 && self.sprite_count < 8 {
+            let entry = &self.oam[n * 4..n * 4 + 4];
+            if is_in_range(entry[0]) {
+                self.sprites[self.sprite_count] = Sprite {
+                    index: n as u8,
+                    y: entry[0],
+                    tile: entry[1],
+                    attributes: entry[2],
+                    x: entry[3],
+                    pattern_lsb: 0,
+                    pattern_msb: 0,
+                };
+                self.sprite_count += 1;
+            }
+            n += 1;
+        }
+
+        // The PPU increments both n and m instead of just n when looking for a 9th sprite
+        let mut m = 0;
+        while n < 64 {
+            if is_in_range(self.oam[n * 4 + m]) {
+                self.status.set_bit(5, true);
+                break;
+            }
+            n += 1;
+            m = (m + 1) % 4;
+        }
+    }
+
+    fn sprite_pattern_address(&self, slot: usize) -> u16 {
+        if slot >= self.sprite_count {
+            // Empty slots fetch tile $FF
+            let table = if self.sprite_height() == 16 || self.ctrl.get_bit(3) {
+                0x1000
+            } else {
+                0x0000
+            };
+            return table | 0x0FF0;
+        }
+
+        let sprite = self.sprites[slot];
+        let mut row = self.scanline.wrapping_sub(sprite.y as u16);
+        if sprite.attributes.get_bit(7) {
+            row = self.sprite_height() as u16 - 1 - row;
+        }
+
+        if self.sprite_height() == 16 {
+            let table = (sprite.tile as u16 & 0x01) * 0x1000;
+            let tile = (sprite.tile & 0xFE) as u16 + row / 8;
+            table | tile << 4 | (row % 8)
+        } else {
+            let table = self.ctrl.get_bit(3) as u16 * 0x1000;
+            table | (sprite.tile as u16) << 4 | row
+        }
+    }
+
+    fn set_sprite_pattern(&mut self, slot: usize, mut pattern: u8, is_msb: bool) {
+        if slot >= self.sprite_count {
+            return;
+        }
+        let sprite = &mut self.sprites[slot];
+        if sprite.attributes.get_bit(6) {
+            pattern = pattern.reverse_bits();
+        }
+        if is_msb {
+            sprite.pattern_msb = pattern;
+        } else {
+            sprite.pattern_lsb = pattern;
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.ctrl.get_bit(5) {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn bg_pattern_address(&self) -> u16 {
+        let table = self.ctrl.get_bit(4) as u16 * 0x1000;
+        table + ((self.bg_next_tile_id as u16) << 4) + self.fine_y()
+    }
+
+    fn load_bg_shifters(&mut self) {
+        self.bg_shifter_pattern_lsb =
+            (self.bg_shifter_pattern_lsb & 0xFF00) | self.bg_next_tile_lsb as u16;
+        self.bg_shifter_pattern_msb =
+            (self.bg_shifter_pattern_msb & 0xFF00) | self.bg_next_tile_msb as u16;
+        let attribute_lsb = if self.bg_next_tile_attribute.get_bit(0) {
+            0xFF
+        } else {
+            0x00
+        };
+        let attribute_msb = if self.bg_next_tile_attribute.get_bit(1) {
+            0xFF
+        } else {
+            0x00
+        };
+        self.bg_shifter_attribute_lsb = (self.bg_shifter_attribute_lsb & 0xFF00) | attribute_lsb;
+        self.bg_shifter_attribute_msb = (self.bg_shifter_attribute_msb & 0xFF00) | attribute_msb;
+    }
+
+    fn update_shifters(&mut self) {
+        if self.mask.get_bit(3) {
+            self.bg_shifter_pattern_lsb <<= 1;
+            self.bg_shifter_pattern_msb <<= 1;
+            self.bg_shifter_attribute_lsb <<= 1;
+            self.bg_shifter_attribute_msb <<= 1;
+        }
+    }
+
+    fn coarse_x(&self) -> u16 {
+        self.v.get_bits(0..=4)
+    }
+
+    fn coarse_y(&self) -> u16 {
+        self.v.get_bits(5..=9)
+    }
+
+    fn fine_y(&self) -> u16 {
+        self.v.get_bits(12..=14)
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.coarse_x() == 31 {
+            self.v.set_bits(0..=4, 0);
+            // Switch horizontal nametable
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.fine_y() < 7 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v.set_bits(12..=14, 0);
+        match self.coarse_y() {
+            29 => {
+                self.v.set_bits(5..=9, 0);
+                // Switch vertical nametable
+                self.v ^= 0x0800;
+            }
+            // Attribute table rows, wraps without switching nametable
+            31 => self.v.set_bits(5..=9, 0),
+            coarse_y => self.v.set_bits(5..=9, coarse_y + 1),
+        }
+    }
+
+    fn copy_horizontal_scroll(&mut self) {
+        self.v.set_bits(0..=4, self.t.get_bits(0..=4));
+        self.v.set_bit(10, self.t.get_bit(10));
+    }
+
+    fn copy_vertical_scroll(&mut self) {
+        self.v.set_bits(5..=9, self.t.get_bits(5..=9));
+        self.v.set_bits(11..=14, self.t.get_bits(11..=14));
+    }
+
+    pub fn read_register(&mut self, address: u16, cartridge: &mut Cartridge) -> u8 {
+        match address & 0x2007 {
+            0x2002 => {
+                let value = (self.status & 0xE0) | (self.io_latch & 0x1F);
+                self.set_vblank(false);
+                self.w = false;
+                self.io_latch = value;
+            }
+            0x2004 => {
+                self.io_latch = self.oam[self.oam_address as usize];
+            }
+            0x2007 => {
+                let address = self.v & 0x3FFF;
+                let value = if address >= 0x3F00 {
+                    // Palette reads are not buffered, but the buffer gets the nametable underneath
+                    self.read_buffer = self.read_memory(address - 0x1000, cartridge);
+                    (self.read_memory(address, cartridge) & 0x3F) | (self.io_latch & 0xC0)
+                } else {
+                    let value = self.read_buffer;
+                    self.read_buffer = self.read_memory(address, cartridge);
+                    value
+                };
+                self.increment_v();
+                self.io_latch = value;
+            }
+            // Write only registers
+            _ => {}
+        }
+        self.io_latch
+    }
+
+    /// Same as `read_register` but without side effects.
+    pub fn peek_register(&self, address: u16) -> u8 {
+        match address & 0x2007 {
+            0x2002 => (self.status & 0xE0) | (self.io_latch & 0x1F),
+            0x2004 => self.oam[self.oam_address as usize],
+            0x2007 => self.read_buffer,
+            _ => self.io_latch,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8, cartridge: &mut Cartridge) {
+        self.io_latch = value;
+        match address & 0x2007 {
+            0x2000 => {
+                let was_nmi_enabled = self.ctrl.get_bit(7);
+                self.ctrl = value;
+                self.t.set_bits(10..=11, value.get_bits(0..=1) as u16);
+                // Enabling NMI during VBlank triggers an NMI right away
+                if !was_nmi_enabled && self.ctrl.get_bit(7) && self.status.get_bit(7) {
+                    self.nmi_pending = true;
+                }
+            }
+            0x2001 => self.mask = value,
+            0x2002 => {}
+            0x2003 => self.oam_address = value,
+            0x2004 => self.write_oam(value),
+            0x2005 => {
+                if !self.w {
+                    self.t.set_bits(0..=4, value.get_bits(3..=7) as u16);
+                    self.x = value.get_bits(0..=2);
+                } else {
+                    self.t.set_bits(12..=14, value.get_bits(0..=2) as u16);
+                    self.t.set_bits(5..=9, value.get_bits(3..=7) as u16);
+                }
+                self.w = !self.w;
+            }
+            0x2006 => {
+                if !self.w {
+                    self.t.set_bits(8..=13, value.get_bits(0..=5) as u16);
+                    self.t.set_bit(14, false);
+                } else {
+                    self.t.set_bits(0..=7, value as u16);
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            0x2007 => {
+                self.write_memory(self.v & 0x3FFF, value, cartridge);
+                self.increment_v();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Used by $2004 and OAM DMA.
+    pub fn write_oam(&mut self, value: u8) {
+        self.oam[self.oam_address as usize] = value;
+        self.oam_address = self.oam_address.wrapping_add(1);
+    }
+
+    pub fn read_memory(&mut self, address: u16, cartridge: &mut Cartridge) -> u8 {
+        match address & 0x3FFF {
+            0x0000..=0x1FFF => cartridge.ppu_read(address),
+            0x2000..=0x3EFF => cartridge.read_nametable(address, &self.vram),
+            0x3F00..=0x3FFF => self.palette[palette_index(address)],
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_memory(&mut self, address: u16, value: u8, cartridge: &mut Cartridge) {
+        match address & 0x3FFF {
+            0x0000..=0x1FFF => cartridge.ppu_write(address, value),
+            0x2000..=0x3EFF => cartridge.write_nametable(address, value, &mut self.vram),
+            0x3F00..=0x3FFF => self.palette[palette_index(address)] = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn increment_v(&mut self) {
+        let is_rendering_scanline =
+            self.scanline < SCREEN_HEIGHT as u16 || self.scanline == PRE_RENDER_SCANLINE;
+        if self.is_rendering_enabled() && is_rendering_scanline {
+            // $2007 accesses during rendering bump both scroll counters
+            self.increment_coarse_x();
+            self.increment_y();
+            return;
+        }
+        let increment = if self.ctrl.get_bit(2) { 32 } else { 1 };
+        self.v = self.v.wrapping_add(increment) & 0x7FFF;
+    }
+
+    fn set_vblank(&mut self, vblank: bool) {
+        let was_nmi_active = self.status.get_bit(7) && self.ctrl.get_bit(7);
+        self.status.set_bit(7, vblank);
+        let is_nmi_active = self.status.get_bit(7) && self.ctrl.get_bit(7);
+        if !was_nmi_active && is_nmi_active {
+            self.nmi_pending = true;
+        }
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// $3F10, $3F14, $3F18 and $3F1C are mirrors of $3F00, $3F04, $3F08 and $3F0C.
+fn palette_index(address: u16) -> usize {
+    let index = address as usize & 0x1F;
+    if index & 0x13 == 0x10 {
+        index & 0x0F
+    } else {
+        index
+    }
+}
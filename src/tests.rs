@@ -3,28 +3,625 @@ mod cpu {
     use std::fs::File;
     use std::io::Read;
 
+    use crate::breakpoints::{self, Breakpoint, BreakpointHit, BreakpointKind, Condition};
+    use crate::bus::{Bus, RamInit, SystemBus};
     use crate::cartridge::Cartridge;
-    use crate::cpu::CPU;
+    use crate::cpu::{CpuState, CPU};
     use crate::util::BitOperations;
 
     #[test]
     fn nestest() {
-        let cartridge = Cartridge::from_file("misc/nestest.nes");
-        let mut cpu = CPU::new(cartridge);
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut cpu = CPU::new(SystemBus::new(cartridge));
         cpu.enable_logging(true);
         cpu.pc = 0xc000;
 
-        // Starting from 0xC6BD, nestest tests illegal instructions that we don't implement
+        let mut nestest_logs_file = File::open("misc/nestest.log").unwrap();
+        let mut nestest_logs = String::new();
+        nestest_logs_file.read_to_string(&mut nestest_logs).unwrap();
+
+        // misc/nestest.log covers the official instructions, up to $C6BD
         while cpu.pc != 0xC6BD {
+            cpu.step();
+        }
+        assert_eq!(cpu.logs, nestest_logs);
+
+        // The last instruction of the automated tests is the RTS at $C66E
+        while cpu.pc != 0xC66E {
+            cpu.step();
+        }
+        // nestest stores the number of the first failed test at $02 for official instructions
+        // and at $03 for unofficial ones
+        assert_eq!(cpu.bus.read(0x0002), 0x00);
+        assert_eq!(cpu.bus.read(0x0003), 0x00);
+        // nestest's complete log has 8991 lines, the last one being this RTS
+        assert_eq!(cpu.logs.lines().count(), 8990);
+        // It reaches the RTS on cycle 26554, counting the 7 cycles of the reset sequence
+        assert_eq!(cpu.cycles, 26554);
+    }
+
+    /// 64 KiB of plain RAM, no mirroring and no devices.
+    #[derive(Debug)]
+    struct FlatBus([u8; 0x10000]);
+
+    impl Bus for FlatBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.0[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.0[address as usize] = value;
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.0[address as usize]
+        }
+    }
+
+    #[test]
+    fn custom_bus() {
+        let mut bus = FlatBus([0; 0x10000]);
+        // Reset vector
+        bus.0[0xFFFC] = 0x00;
+        bus.0[0xFFFD] = 0x80;
+        // LDA #$42; STA $6000
+        bus.0[0x8000..0x8005].copy_from_slice(&[0xA9, 0x42, 0x8D, 0x00, 0x60]);
+
+        let mut cpu = CPU::new(bus);
+        assert_eq!(cpu.pc, 0x8000);
+        for _ in 0..6 {
             cpu.clock();
         }
-        let my_logs = cpu.logs;
+        assert_eq!(cpu.bus.0[0x6000], 0x42);
+    }
 
-        let mut nestest_logs_file = File::open("misc/nestest.log").unwrap();
-        let mut nestest_logs = String::new();
-        nestest_logs_file.read_to_string(&mut nestest_logs).unwrap();
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    /// A `FlatBus` recording every access.
+    #[derive(Debug)]
+    struct RecordingBus {
+        memory: FlatBus,
+        accesses: Vec<Access>,
+    }
+
+    impl Bus for RecordingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.accesses.push(Access::Read(address));
+            self.memory.read(address)
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.accesses.push(Access::Write(address, value));
+            self.memory.write(address, value);
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory.peek(address)
+        }
+    }
+
+    #[test]
+    fn bus_accesses() {
+        let mut memory = FlatBus([0; 0x10000]);
+        memory.0[0xFFFC] = 0x00;
+        memory.0[0xFFFD] = 0x80;
+        // LDX #$01; LDA $02FF,X; INC $0300
+        memory.0[0x8000..0x8008].copy_from_slice(&[0xA2, 0x01, 0xBD, 0xFF, 0x02, 0xEE, 0x00, 0x03]);
+        memory.0[0x0300] = 0x41;
+
+        let mut cpu = CPU::new(RecordingBus {
+            memory,
+            accesses: vec![],
+        });
+        cpu.bus.accesses.clear();
+        for _ in 0..2 + 5 + 6 {
+            cpu.clock();
+        }
+        assert!(cpu.at_instruction_boundary());
+
+        use Access::*;
+        assert_eq!(
+            cpu.bus.accesses,
+            [
+                Read(0x8000),
+                Read(0x8001),
+                Read(0x8002),
+                Read(0x8003),
+                Read(0x8004),
+                // The page crossing reads the address before fixing its high byte
+                Read(0x0200),
+                Read(0x0300),
+                Read(0x8005),
+                Read(0x8006),
+                Read(0x8007),
+                Read(0x0300),
+                // Read-modify-write instructions write the original value first
+                Write(0x0300, 0x41),
+                Write(0x0300, 0x42),
+            ]
+        );
+    }
+
+    #[test]
+    fn power_on_and_reset() {
+        let mut memory = FlatBus([0; 0x10000]);
+        memory.0[0xFFFC] = 0x34;
+        memory.0[0xFFFD] = 0x12;
+        let mut cpu = CPU::new(RecordingBus {
+            memory,
+            accesses: vec![],
+        });
+
+        use Access::*;
+        // The reset sequence reads the stack instead of writing it
+        assert_eq!(
+            cpu.bus.accesses,
+            [
+                Read(0x0000),
+                Read(0x0000),
+                Read(0x0100),
+                Read(0x01FF),
+                Read(0x01FE),
+                Read(0xFFFC),
+                Read(0xFFFD),
+            ]
+        );
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(cpu.s, 0xFD);
+        assert_eq!(cpu.flags.to_byte(), 0x24);
+        assert_eq!(cpu.cycles, 7);
+
+        cpu.a = 1;
+        cpu.x = 2;
+        cpu.y = 3;
+        cpu.flags.interrupt_disable = false;
+        cpu.flags.carry = true;
+        cpu.pc = 0x4000;
+        cpu.reset();
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!((cpu.a, cpu.x, cpu.y), (1, 2, 3));
+        assert_eq!(cpu.s, 0xFA);
+        assert!(cpu.flags.interrupt_disable && cpu.flags.carry);
+        assert_eq!(cpu.cycles, 14);
+
+        cpu.power_on();
+        assert_eq!((cpu.a, cpu.x, cpu.y, cpu.s), (0, 0, 0, 0xFD));
+        assert_eq!(cpu.cycles, 7);
+    }
+
+    #[test]
+    fn ram_init() {
+        let mut ram = [0x55; 16];
+        RamInit::Zeros.fill(&mut ram);
+        assert_eq!(ram, [0x00; 16]);
+        RamInit::Ones.fill(&mut ram);
+        assert_eq!(ram, [0xFF; 16]);
+        RamInit::Console.fill(&mut ram);
+        assert_eq!(ram[..8], [0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(ram[8..], ram[..8]);
+
+        // Random patterns only depend on the seed
+        let mut other = [0; 16];
+        RamInit::Random { seed: 42 }.fill(&mut ram);
+        RamInit::Random { seed: 42 }.fill(&mut other);
+        assert_eq!(ram, other);
+        RamInit::Random { seed: 43 }.fill(&mut other);
+        assert_ne!(ram, other);
+
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let bus = SystemBus::with_ram_init(cartridge, RamInit::Ones);
+        assert_eq!(bus.peek(0x07FF), 0xFF);
+    }
+
+    #[test]
+    fn oam_dma() {
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut cpu = CPU::new(SystemBus::new(cartridge));
+        for (i, byte) in cpu.bus.ram[0x0200..0x0300].iter_mut().enumerate() {
+            *byte = i as u8 ^ 0x5A;
+        }
+        // LDA #$02; STA $4014; NOP; STA $4014
+        cpu.bus.ram[0x0300..0x0309]
+            .copy_from_slice(&[0xA9, 0x02, 0x8D, 0x14, 0x40, 0xEA, 0x8D, 0x14, 0x40]);
+        cpu.pc = 0x0300;
+        cpu.step();
+
+        // The CPU is halted for a cycle, plus one when the first read would be on a put cycle,
+        // then reads and writes a byte every 2 cycles
+        let dma_cycles = |cpu: &mut CPU<SystemBus>| {
+            let start = cpu.cycles;
+            let write_cycle = start + 3;
+            cpu.step();
+            (write_cycle % 2, cpu.cycles - start - 4)
+        };
+        let first = dma_cycles(&mut cpu);
+        assert_eq!(cpu.bus.ppu.oam[..], cpu.bus.ram[0x0200..0x0300]);
+        assert_eq!(first.1, 513 + first.0);
+
+        // With the 513 cycles of the first DMA, the 2 of the NOP change the alignment
+        cpu.bus.ppu.oam.fill(0);
+        cpu.step();
+        let second = dma_cycles(&mut cpu);
+        assert_eq!(cpu.bus.ppu.oam[..], cpu.bus.ram[0x0200..0x0300]);
+        assert_eq!(second.1, 513 + second.0);
+        assert_ne!(first.0, second.0);
+        assert!(cpu.at_instruction_boundary());
+        assert_eq!(cpu.pc, 0x0309);
+    }
+
+    #[test]
+    fn instruction_cycles() {
+        let mut bus = FlatBus([0; 0x10000]);
+        bus.0[0xFFFC] = 0x00;
+        bus.0[0xFFFD] = 0x80;
+        let mut cpu = CPU::new(bus);
+
+        let mut cycles = |program: &[u8]| {
+            cpu.bus.0[0x8000..0x8000 + program.len()].copy_from_slice(program);
+            cpu.pc = 0x8000;
+            let start = cpu.cycles;
+            cpu.step();
+            cpu.cycles - start
+        };
+
+        // LDA $10,X
+        assert_eq!(cycles(&[0xB5, 0x10]), 4);
+        // LDA $1000,Y without and with a page crossing
+        assert_eq!(cycles(&[0xB9, 0x00, 0x10]), 4);
+        assert_eq!(cycles(&[0xA0, 0x01]), 2);
+        assert_eq!(cycles(&[0xB9, 0xFF, 0x10]), 5);
+        // STA $1000,Y always takes the extra cycle
+        assert_eq!(cycles(&[0x99, 0x00, 0x10]), 5);
+        // ASL $1000,X
+        assert_eq!(cycles(&[0x1E, 0x00, 0x10]), 7);
+        // LDA ($10),Y, STA ($10,X)
+        assert_eq!(cycles(&[0xB1, 0x10]), 5);
+        assert_eq!(cycles(&[0x81, 0x10]), 6);
+        // JSR, RTS, JMP ($1000)
+        assert_eq!(cycles(&[0x20, 0x00, 0x90]), 6);
+        assert_eq!(cycles(&[0x60]), 6);
+        assert_eq!(cycles(&[0x6C, 0x00, 0x10]), 5);
+        // PHA, PLA
+        assert_eq!(cycles(&[0x48]), 3);
+        assert_eq!(cycles(&[0x68]), 4);
+        // BNE not taken, taken, taken to another page
+        assert_eq!(cycles(&[0xA9, 0x00]), 2);
+        assert_eq!(cycles(&[0xD0, 0x10]), 2);
+        assert_eq!(cycles(&[0xF0, 0x10]), 3);
+        assert_eq!(cycles(&[0xF0, 0x80]), 4);
+    }
+
+    /// Runs `program` from $8000 on a `FlatBus` until it has been entirely executed.
+    fn execute(program: &[u8], setup: impl FnOnce(&mut FlatBus)) -> CPU<FlatBus> {
+        let mut bus = FlatBus([0; 0x10000]);
+        bus.0[0xFFFC] = 0x00;
+        bus.0[0xFFFD] = 0x80;
+        bus.0[0x8000..0x8000 + program.len()].copy_from_slice(program);
+        setup(&mut bus);
+
+        let mut cpu = CPU::new(bus);
+        while cpu.pc < 0x8000 + program.len() as u16 {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn unofficial_opcodes() {
+        // ANC #$80
+        let cpu = execute(&[0xA9, 0xFF, 0x0B, 0x80], |_| {});
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.flags.carry && cpu.flags.negative);
+
+        // ALR #$81
+        let cpu = execute(&[0xA9, 0xFF, 0x4B, 0x81], |_| {});
+        assert_eq!(cpu.a, 0x40);
+        assert!(cpu.flags.carry);
+
+        // SEC, ARR #$C0
+        let cpu = execute(&[0x38, 0xA9, 0xFF, 0x6B, 0xC0], |_| {});
+        assert_eq!(cpu.a, 0xE0);
+        assert!(cpu.flags.carry && !cpu.flags.overflow);
+
+        // AXS #$10
+        let cpu = execute(&[0xA9, 0xF0, 0xA2, 0x3C, 0xCB, 0x10], |_| {});
+        assert_eq!(cpu.x, 0x20);
+        assert!(cpu.flags.carry);
+
+        // XAA #$0F
+        let cpu = execute(&[0xA9, 0x00, 0xA2, 0xFF, 0x8B, 0x0F], |_| {});
+        assert_eq!(cpu.a, 0x0E);
+
+        // LAS $0200,Y
+        let cpu = execute(&[0xBB, 0x00, 0x02], |bus| bus.0[0x0200] = 0x3C);
+        assert_eq!((cpu.a, cpu.x, cpu.s), (0x3C, 0x3C, 0x3C));
+
+        // SHX $0200,Y
+        let cpu = execute(&[0xA2, 0x55, 0xA0, 0x01, 0x9E, 0x00, 0x02], |_| {});
+        assert_eq!(cpu.bus.0[0x0201], 0x55 & 0x03);
+        // SHX $02FF,Y crosses a page, the value becomes the high byte of the address
+        let cpu = execute(&[0xA2, 0x55, 0xA0, 0x01, 0x9E, 0xFF, 0x02], |_| {});
+        assert_eq!(cpu.bus.0[0x0100], 0x01);
+        assert_eq!(cpu.bus.0[0x0300], 0x00);
+
+        // SHY $0600,X
+        let cpu = execute(&[0xA0, 0xFF, 0xA2, 0x00, 0x9C, 0x00, 0x06], |_| {});
+        assert_eq!(cpu.bus.0[0x0600], 0x07);
+
+        // TAS $0400,Y
+        let cpu = execute(&[0xA9, 0xF7, 0xA2, 0x7F, 0x9B, 0x00, 0x04], |_| {});
+        assert_eq!(cpu.s, 0x77);
+        assert_eq!(cpu.bus.0[0x0400], 0x05);
+
+        // SHA ($10),Y
+        let cpu = execute(&[0xA9, 0xFF, 0xA2, 0xFF, 0x93, 0x10], |bus| {
+            bus.0[0x11] = 0x07
+        });
+        assert_eq!(cpu.bus.0[0x0700], 0x08);
+    }
+
+    #[test]
+    fn jam() {
+        // LDA #$01, JAM
+        let mut cpu = execute(&[0xA9, 0x01], |bus| bus.0[0x8002] = 0x02);
+        assert_eq!(
+            cpu.step(),
+            CpuState::Jammed {
+                pc: 0x8002,
+                opcode: 0x02
+            }
+        );
+        for _ in 0..10 {
+            assert_eq!(
+                cpu.clock(),
+                CpuState::Jammed {
+                    pc: 0x8002,
+                    opcode: 0x02
+                }
+            );
+        }
+        assert_eq!(cpu.pc, 0x8002);
+        assert_eq!(cpu.a, 0x01);
+
+        cpu.reset();
+        assert_eq!(cpu.step(), CpuState::Running);
+        assert_eq!(cpu.a, 0x01);
+    }
+
+    /// A `FlatBus` with interrupt lines driven by the test, writing to $5000 acknowledges the IRQ.
+    /// The interrupt tests reproduce the scenarios of blargg's cpu_interrupts ROMs, which
+    /// aren't run: they aren't in misc/ and need the APU frame IRQ and DMA, not emulated yet.
+    #[derive(Debug)]
+    struct InterruptBus {
+        memory: FlatBus,
+        cycle: u64,
+        /// IRQ is asserted from the end of this cycle, counted from 1
+        irq_from: Option<u64>,
+        /// NMI edge at the end of this cycle
+        nmi_at: Option<u64>,
+    }
+
+    impl Bus for InterruptBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory.read(address)
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            if address == 0x5000 {
+                self.irq_from = None;
+            }
+            self.memory.write(address, value);
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory.peek(address)
+        }
+
+        fn tick(&mut self) {
+            self.cycle += 1;
+        }
+
+        fn poll_nmi(&mut self) -> bool {
+            self.nmi_at == Some(self.cycle)
+        }
+
+        fn irq(&self) -> bool {
+            self.irq_from.is_some_and(|cycle| self.cycle >= cycle)
+        }
+    }
+
+    /// A CPU with interrupts enabled about to run `program` from $8000, in memory full of NOPs.
+    /// The IRQ handler is at $9000 and the NMI handler at $A000.
+    fn interrupt_cpu(
+        program: &[u8],
+        irq_from: Option<u64>,
+        nmi_at: Option<u64>,
+    ) -> CPU<InterruptBus> {
+        let mut memory = FlatBus([0xEA; 0x10000]);
+        memory.0[0xFFFA..].copy_from_slice(&[0x00, 0xA0, 0x00, 0x80, 0x00, 0x90]);
+        memory.0[0x8000..0x8000 + program.len()].copy_from_slice(program);
+
+        let mut cpu = CPU::new(InterruptBus {
+            memory,
+            cycle: 0,
+            irq_from: None,
+            nmi_at: None,
+        });
+        cpu.flags.interrupt_disable = false;
+        // Count the cycles from the end of the reset sequence
+        cpu.cycles = 0;
+        cpu.bus.cycle = 0;
+        cpu.bus.irq_from = irq_from;
+        cpu.bus.nmi_at = nmi_at;
+        cpu
+    }
+
+    /// Runs `program` until an interrupt handler is reached.
+    /// Returns the CPU and the return address and flags pushed by the interrupt.
+    fn interrupt(
+        program: &[u8],
+        irq_from: Option<u64>,
+        nmi_at: Option<u64>,
+    ) -> (CPU<InterruptBus>, u16, u8) {
+        let mut cpu = interrupt_cpu(program, irq_from, nmi_at);
+        while cpu.pc < 0x9000 {
+            cpu.step();
+        }
+
+        let stack = &cpu.bus.memory.0[0x1FB..=0x1FD];
+        let pc = u16::from_le_bytes([stack[1], stack[2]]);
+        let flags = stack[0];
+        (cpu, pc, flags)
+    }
+
+    #[test]
+    fn interrupt_latency() {
+        // IRQ asserted during the last cycle of LDA #$00 is serviced after the next NOP
+        let (cpu, pc, flags) = interrupt(&[0xA9, 0x00], Some(2), None);
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(pc, 0x8003);
+        // B flag is clear for hardware interrupts
+        assert!(!flags.get_bit(4));
+        // The interrupt sequence takes 7 cycles
+        assert_eq!(cpu.cycles, 2 + 2 + 7);
+
+        // During the opcode fetch, it's serviced right after LDA
+        let (_, pc, _) = interrupt(&[0xA9, 0x00], Some(1), None);
+        assert_eq!(pc, 0x8002);
 
-        assert_eq!(my_logs, nestest_logs);
+        // NMI works the same
+        let (cpu, pc, _) = interrupt(&[0xA9, 0x00], None, Some(1));
+        assert_eq!(cpu.pc, 0xA000);
+        assert_eq!(pc, 0x8002);
+        assert!(!cpu.nmi_pending);
+    }
+
+    #[test]
+    fn interrupt_flag_delay() {
+        // SEI, CLI: the IRQ asserted during CLI is serviced after the NOP following it
+        let (_, pc, _) = interrupt(&[0x78, 0x58], Some(3), None);
+        assert_eq!(pc, 0x8003);
+
+        // The IRQ polled before SEI changes the flag is serviced right after it
+        let (_, pc, flags) = interrupt(&[0x78], Some(1), None);
+        assert_eq!(pc, 0x8001);
+        assert!(flags.get_bit(2));
+
+        // PLP clearing the I flag delays the IRQ too
+        // LDA #$00; PHA; SEI; PLP
+        let (_, pc, _) = interrupt(&[0xA9, 0x00, 0x48, 0x78, 0x28], Some(8), None);
+        assert_eq!(pc, 0x8006);
+
+        // RTI restores the I flag immediately
+        let mut program = vec![
+            0xA9, 0x80, 0x48, 0xA9, 0x10, 0x48, 0xA9, 0x00, 0x48, 0x78, 0x40,
+        ];
+        program.resize(0x10, 0xEA);
+        // LDA #$80; PHA; LDA #$10; PHA; LDA #$00; PHA; SEI; RTI to $8010
+        let (_, pc, _) = interrupt(&program, Some(18), None);
+        assert_eq!(pc, 0x8010);
+    }
+
+    #[test]
+    fn nmi_hijacking() {
+        // BRK
+        let brk = [0x00, 0xFF];
+
+        // An NMI during the first cycles of BRK takes over its vector, B is still set
+        let (cpu, pc, flags) = interrupt(&brk, None, Some(3));
+        assert_eq!(cpu.pc, 0xA000);
+        assert_eq!(pc, 0x8002);
+        assert!(flags.get_bit(4));
+        assert!(!cpu.nmi_pending);
+
+        // Later, BRK completes and the NMI is serviced after the first instruction of the handler
+        let (mut cpu, pc, _) = interrupt(&brk, None, Some(6));
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(pc, 0x8002);
+        assert!(cpu.nmi_pending);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9001);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xA000);
+
+        // NMI hijacks IRQ the same way, B stays clear
+        let (cpu, pc, flags) = interrupt(&[0xA9, 0x00], Some(1), Some(4));
+        assert_eq!(cpu.pc, 0xA000);
+        assert_eq!(pc, 0x8002);
+        assert!(!flags.get_bit(4));
+    }
+
+    #[test]
+    fn brk() {
+        // BRK runs even with interrupts disabled, and skips its padding byte
+        let mut cpu = interrupt_cpu(&[0x78, 0x00, 0xFF], None, None);
+        cpu.break_on_brk = true;
+        assert_eq!(cpu.step(), CpuState::Running);
+        assert_eq!(cpu.step(), CpuState::Break { pc: 0x8001 });
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(cpu.cycles, 2 + 7);
+
+        // Return address and P with the B flag set
+        assert_eq!(cpu.bus.memory.0[0x1FB..=0x1FD], [0x34, 0x03, 0x80]);
+        assert!(cpu.flags.interrupt_disable);
+        assert_eq!(cpu.step(), CpuState::Running);
+    }
+
+    #[test]
+    fn brk_in_irq_handler() {
+        // JMP $8000
+        let mut cpu = interrupt_cpu(&[0x4C, 0x00, 0x80], Some(1), None);
+        #[rustfmt::skip]
+        let handler = [
+            0x48,             // 9000 PHA
+            0xBA,             // 9001 TSX
+            0xBD, 0x02, 0x01, // 9002 LDA $0102,X  pushed P
+            0x29, 0x10,       // 9005 AND #$10
+            0xD0, 0x09,       // 9007 BNE $9012
+            0x8D, 0x00, 0x50, // 9009 STA $5000    acknowledge the IRQ
+            0x00, 0xFF,       // 900C BRK
+            0xE6, 0x10,       // 900E INC $10
+            0x68,             // 9010 PLA
+            0x40,             // 9011 RTI
+            0xE6, 0x11,       // 9012 INC $11      BRK handler
+            0x68,             // 9014 PLA
+            0x40,             // 9015 RTI
+        ];
+        cpu.bus.memory.0[0x9000..0x9000 + handler.len()].copy_from_slice(&handler);
+        cpu.bus.memory.0[0x10..=0x11].fill(0);
+
+        for _ in 0..40 {
+            cpu.step();
+        }
+        // Both handlers ran once and returned, BRK returned past its padding byte
+        assert_eq!(cpu.bus.memory.0[0x10], 1);
+        assert_eq!(cpu.bus.memory.0[0x11], 1);
+        assert!(cpu.pc < 0x8003);
+        assert_eq!(cpu.s, 0xFD);
+        assert!(!cpu.flags.interrupt_disable);
+    }
+
+    #[test]
+    fn branch_interrupt_delay() {
+        // LDA #$00; BEQ +0
+        let program = [0xA9, 0x00, 0xF0, 0x00];
+
+        // A taken branch without page crossing doesn't poll on its last cycle,
+        // the IRQ waits for the next instruction
+        let (_, pc, _) = interrupt(&program, Some(4), None);
+        assert_eq!(pc, 0x8005);
+
+        // Before its second cycle, it's serviced after the branch
+        let (_, pc, _) = interrupt(&program, Some(3), None);
+        assert_eq!(pc, 0x8004);
+
+        // LDA #$00; BEQ +0 not taken
+        let (_, pc, _) = interrupt(&[0xA9, 0x01, 0xF0, 0x00], Some(3), None);
+        assert_eq!(pc, 0x8004);
     }
 
     #[test]
@@ -39,4 +636,1758 @@ mod cpu {
         result.set_bits(8..=15, c.get_bits(0..=7));
         assert_eq!(result, 0b1100_1100_1010_0101);
     }
+
+    /// LDX #$00; INX; CPX #$05; BNE -5; STX $0200; LDA $0200
+    const LOOP: [u8; 13] = [
+        0xA2, 0x00, 0xE8, 0xE0, 0x05, 0xD0, 0xFB, 0x8E, 0x00, 0x02, 0xAD, 0x00, 0x02,
+    ];
+
+    fn loop_cpu() -> CPU<FlatBus> {
+        let mut bus = FlatBus([0; 0x10000]);
+        bus.0[0xFFFC] = 0x00;
+        bus.0[0xFFFD] = 0x80;
+        bus.0[0x8000..0x8000 + LOOP.len()].copy_from_slice(&LOOP);
+        CPU::new(bus)
+    }
+
+    /// Steps until something stops the CPU or the program ends.
+    fn run(cpu: &mut CPU<FlatBus>) -> CpuState {
+        while cpu.pc < 0x8000 + LOOP.len() as u16 {
+            let state = cpu.step();
+            if state != CpuState::Running {
+                return state;
+            }
+        }
+        CpuState::Running
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = loop_cpu();
+        let mut breakpoint = Breakpoint::execute(0x8003);
+        breakpoint.condition = Some(Condition::parse("x == 3").unwrap());
+        let id = cpu.breakpoints.add(breakpoint);
+        assert!(!cpu.breakpoints.watches(breakpoints::Access::Read));
+
+        // Stops before the CPX runs
+        let hit = BreakpointHit {
+            id,
+            access: breakpoints::Access::Execute,
+            address: 0x8003,
+            value: 0xE0,
+        };
+        assert_eq!(run(&mut cpu), CpuState::Breakpoint(hit));
+        assert_eq!(cpu.pc, 0x8003);
+        assert_eq!(cpu.x, 3);
+        assert!(cpu.at_instruction_boundary());
+        // Resuming runs the instruction it stopped on
+        assert_eq!(run(&mut cpu), CpuState::Running);
+        assert_eq!(cpu.breakpoints.get(id).unwrap().hits, 1);
+
+        // Hit counts
+        let mut cpu = loop_cpu();
+        let mut breakpoint = Breakpoint::execute(0x8002);
+        breakpoint.ignore_count = 2;
+        let id = cpu.breakpoints.add(breakpoint);
+        assert!(matches!(run(&mut cpu), CpuState::Breakpoint(hit) if hit.id == id));
+        assert_eq!(cpu.x, 2);
+        assert!(matches!(run(&mut cpu), CpuState::Breakpoint(_)));
+        assert_eq!(cpu.x, 3);
+        assert_eq!(cpu.breakpoints.get(id).unwrap().hits, 4);
+
+        // Disabled and removed breakpoints don't stop
+        assert!(cpu.breakpoints.set_enabled(id, false));
+        assert!(!cpu.breakpoints.watches(breakpoints::Access::Execute));
+        assert_eq!(run(&mut cpu), CpuState::Running);
+        assert!(cpu.breakpoints.remove(id).is_some());
+        assert!(cpu.breakpoints.is_empty());
+        assert!(!cpu.breakpoints.set_enabled(id, true));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut cpu = loop_cpu();
+        let write = cpu
+            .breakpoints
+            .add(Breakpoint::new(BreakpointKind::Write, 0x0200..=0x02FF));
+        let mut breakpoint = Breakpoint::new(BreakpointKind::ReadWrite, 0x0200..=0x0200);
+        breakpoint.condition = Some(Condition::parse("value == 5 && pc == $800D").unwrap());
+        let read = cpu.breakpoints.add(breakpoint);
+        assert!(cpu.breakpoints.watches(breakpoints::Access::Read));
+        assert!(!cpu.breakpoints.watches(breakpoints::Access::Execute));
+
+        // Instruction steps stop after the instruction that hit the watchpoint
+        assert_eq!(
+            run(&mut cpu),
+            CpuState::Breakpoint(BreakpointHit {
+                id: write,
+                access: breakpoints::Access::Write,
+                address: 0x0200,
+                value: 5,
+            })
+        );
+        assert_eq!(cpu.pc, 0x800A);
+        assert_eq!(
+            run(&mut cpu),
+            CpuState::Breakpoint(BreakpointHit {
+                id: read,
+                access: breakpoints::Access::Read,
+                address: 0x0200,
+                value: 5,
+            })
+        );
+        assert_eq!(cpu.a, 5);
+        // The write didn't match the condition of the second watchpoint
+        assert_eq!(cpu.breakpoints.get(read).unwrap().hits, 1);
+
+        // Cycle steps stop right after the access
+        let mut cpu = loop_cpu();
+        cpu.breakpoints
+            .add(Breakpoint::new(BreakpointKind::Read, 0x8001..=0x8001));
+        assert!(matches!(cpu.clock(), CpuState::Running));
+        assert!(matches!(cpu.clock(), CpuState::Breakpoint(_)));
+        assert_eq!(cpu.pc, 0x8002);
+    }
+}
+
+#[cfg(test)]
+mod ppu {
+    use crate::cartridge::Cartridge;
+    use crate::ppu::{PPU, VBLANK_SCANLINE};
+
+    #[test]
+    fn ppudata_reads_are_buffered() {
+        let mut cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut ppu = PPU::new();
+
+        ppu.write_register(0x2006, 0x21, &mut cartridge);
+        ppu.write_register(0x2006, 0x08, &mut cartridge);
+        ppu.write_register(0x2007, 0xAB, &mut cartridge);
+        ppu.write_register(0x2007, 0xCD, &mut cartridge);
+        assert_eq!(ppu.v, 0x210A);
+
+        ppu.write_register(0x2006, 0x21, &mut cartridge);
+        ppu.write_register(0x2006, 0x08, &mut cartridge);
+        ppu.read_register(0x2007, &mut cartridge);
+        assert_eq!(ppu.read_register(0x2007, &mut cartridge), 0xAB);
+        assert_eq!(ppu.read_register(0x2007, &mut cartridge), 0xCD);
+    }
+
+    #[test]
+    fn scroll_writes_fill_loopy_registers() {
+        let mut cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut ppu = PPU::new();
+
+        ppu.write_register(0x2000, 0b0000_0010, &mut cartridge);
+        ppu.write_register(0x2005, 0b0111_1101, &mut cartridge);
+        ppu.write_register(0x2005, 0b0101_1110, &mut cartridge);
+        // Fine Y 110, nametable 10, coarse Y 01011, coarse X 01111
+        assert_eq!(ppu.t, 0b110_1001_0110_1111);
+        assert_eq!(ppu.x, 0b101);
+        assert!(!ppu.w);
+    }
+
+    #[test]
+    fn vblank_triggers_nmi() {
+        let mut cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut ppu = PPU::new();
+        ppu.write_register(0x2000, 0x80, &mut cartridge);
+
+        while !(ppu.scanline == VBLANK_SCANLINE && ppu.dot == 2) {
+            assert!(!ppu.poll_nmi());
+            ppu.clock(&mut cartridge);
+        }
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        // Reading PPUSTATUS clears the VBlank flag
+        assert_eq!(ppu.read_register(0x2002, &mut cartridge) & 0x80, 0x80);
+        assert_eq!(ppu.read_register(0x2002, &mut cartridge) & 0x80, 0);
+    }
+}
+
+#[cfg(test)]
+mod rendering {
+    use crate::cartridge::Cartridge;
+    use crate::ppu::{PPU, SCREEN_WIDTH};
+
+    /// Fills the first nametable with nestest's tile 3, whose row 2 is fully opaque.
+    fn setup(ppu: &mut PPU, cartridge: &mut Cartridge) {
+        ppu.write_register(0x2006, 0x20, cartridge);
+        ppu.write_register(0x2006, 0x00, cartridge);
+        for _ in 0..960 {
+            ppu.write_register(0x2007, 0x03, cartridge);
+        }
+        ppu.write_register(0x2006, 0x3F, cartridge);
+        ppu.write_register(0x2006, 0x00, cartridge);
+        for &color in &[0x0F, 0x01, 0x02, 0x30] {
+            ppu.write_register(0x2007, color, cartridge);
+        }
+        ppu.write_register(0x2006, 0x3F, cartridge);
+        ppu.write_register(0x2006, 0x13, cartridge);
+        ppu.write_register(0x2007, 0x16, cartridge);
+        ppu.write_register(0x2006, 0x00, cartridge);
+        ppu.write_register(0x2006, 0x00, cartridge);
+    }
+
+    fn run_frame(ppu: &mut PPU, cartridge: &mut Cartridge) {
+        while !ppu.poll_frame_complete() {
+            ppu.clock(cartridge);
+        }
+    }
+
+    #[test]
+    fn background() {
+        let mut cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut ppu = PPU::new();
+        setup(&mut ppu, &mut cartridge);
+        ppu.write_register(0x2001, 0b0000_1010, &mut cartridge);
+        run_frame(&mut ppu, &mut cartridge);
+        run_frame(&mut ppu, &mut cartridge);
+
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[0], 0x0F);
+        assert_eq!(frame[2 * SCREEN_WIDTH], 0x30);
+        assert_eq!(frame[10 * SCREEN_WIDTH + 255], 0x30);
+        assert_eq!(frame[11 * SCREEN_WIDTH + 255], 0x0F);
+        assert_eq!(&ppu.frame_buffer_rgb()[..3], &[0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn sprite_zero_hit() {
+        let mut cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut ppu = PPU::new();
+        setup(&mut ppu, &mut cartridge);
+        // Sprite 0 opaque on scanline 10, behind the background
+        ppu.oam[0..4].copy_from_slice(&[7, 0x03, 0b0010_0000, 40]);
+        // Sprite 1 opaque on scanline 21, in front of the background
+        ppu.oam[4..8].copy_from_slice(&[18, 0x03, 0b0000_0000, 40]);
+        ppu.write_register(0x2001, 0b0001_1110, &mut cartridge);
+        run_frame(&mut ppu, &mut cartridge);
+        run_frame(&mut ppu, &mut cartridge);
+
+        assert_eq!(ppu.peek_register(0x2002) & 0x40, 0x40);
+        let frame = ppu.frame_buffer();
+        assert_eq!(frame[10 * SCREEN_WIDTH + 40], 0x30);
+        assert_eq!(frame[21 * SCREEN_WIDTH + 40], 0x16);
+        assert_eq!(frame[21 * SCREEN_WIDTH + 48], 0x0F);
+    }
+
+    #[test]
+    fn sprite_overflow() {
+        let mut cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut ppu = PPU::new();
+        for sprite in ppu.oam.chunks_mut(4) {
+            sprite.copy_from_slice(&[0xFF, 0, 0, 0]);
+        }
+        for i in 0..9 {
+            ppu.oam[i * 4] = 50;
+        }
+        ppu.write_register(0x2001, 0b0001_0000, &mut cartridge);
+        run_frame(&mut ppu, &mut cartridge);
+        assert_eq!(ppu.peek_register(0x2002) & 0x20, 0x20);
+    }
+}
+
+#[cfg(test)]
+mod cartridge {
+    use std::fs;
+
+    use crate::bus::{Bus, SystemBus};
+    use crate::cartridge::{Cartridge, CartridgeError, Mirroring};
+    use crate::cpu::CPU;
+
+    /// An iNES image where every byte of a 16 KiB PRG ROM bank holds the bank number.
+    pub fn rom(mapper: u8, prg_banks: u8, chr_banks: u8, flags_6: u8) -> Vec<u8> {
+        let mut rom = b"NES\x1A".to_vec();
+        rom.extend_from_slice(&[prg_banks, chr_banks, flags_6 | (mapper << 4), mapper & 0xF0]);
+        rom.resize(16, 0);
+        for bank in 0..prg_banks {
+            rom.resize(rom.len() + 16 * 1024, bank);
+        }
+        rom.resize(rom.len() + chr_banks as usize * 8 * 1024, 0);
+        rom
+    }
+
+    #[test]
+    fn chr_rom() {
+        let mut cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        // Tile 3, row 2 of both planes
+        assert_eq!(cartridge.ppu_read(0x0032), 0xFF);
+        assert_eq!(cartridge.ppu_read(0x003A), 0xFF);
+        // CHR ROM is read only
+        cartridge.ppu_write(0x0032, 0x00);
+        assert_eq!(cartridge.ppu_read(0x0032), 0xFF);
+    }
+
+    #[test]
+    fn mirroring() {
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        assert_eq!(cartridge.mirroring(), Mirroring::Horizontal);
+
+        let nametables = [0x2000, 0x2400, 0x2800, 0x2C00, 0x2FFF];
+        let map = |mirroring: Mirroring| nametables.map(|a| mirroring.nametable_address(a));
+        assert_eq!(
+            map(Mirroring::Horizontal),
+            [0x000, 0x000, 0x400, 0x400, 0x7FF]
+        );
+        assert_eq!(
+            map(Mirroring::Vertical),
+            [0x000, 0x400, 0x000, 0x400, 0x7FF]
+        );
+        assert_eq!(
+            map(Mirroring::SingleScreenA),
+            [0x000, 0x000, 0x000, 0x000, 0x3FF]
+        );
+        assert_eq!(
+            map(Mirroring::SingleScreenB),
+            [0x400, 0x400, 0x400, 0x400, 0x7FF]
+        );
+        assert_eq!(
+            map(Mirroring::FourScreen),
+            [0x000, 0x400, 0x800, 0xC00, 0xFFF]
+        );
+    }
+
+    #[test]
+    fn battery_backed_prg_ram() {
+        let rom_path = std::env::temp_dir().join("nesmulator_battery_test.nes");
+        let save_path = rom_path.with_extension("sav");
+        let _ = fs::remove_file(&save_path);
+        fs::write(&rom_path, rom(0, 1, 1, 0b0000_0010)).unwrap();
+
+        let mut cartridge = Cartridge::from_file(&rom_path).unwrap();
+        assert!(cartridge.has_battery());
+        assert_eq!(cartridge.prg_ram().len(), 8 * 1024);
+        cartridge.write(0x6000, 0x12);
+        cartridge.write(0x7FFF, 0x34);
+        cartridge.save().unwrap();
+
+        let cartridge = Cartridge::from_file(&rom_path).unwrap();
+        assert_eq!(cartridge.read(0x6000), Some(0x12));
+        assert_eq!(cartridge.read(0x7FFF), Some(0x34));
+        assert_eq!(cartridge.read(0x5000), None);
+
+        fs::remove_file(&rom_path).unwrap();
+        fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn small_prg_rom() {
+        for mapper in [0, 1, 2, 3, 4, 7] {
+            // NES 2.0 exponent-multiplier size, 2^13 * 1
+            let mut image = rom(mapper, 0, 0, 0);
+            image[4] = 13 << 2;
+            image[7] |= 0b0000_1000;
+            image[9] = 0x0F;
+            image.extend((0..8 * 1024).map(|i| (i >> 8) as u8));
+            let mut cartridge = Cartridge::from_bytes(&image).unwrap();
+            assert_eq!(cartridge.header().prg_rom_size, 8 * 1024);
+
+            // Mirrored across the whole $8000-$FFFF window, whatever the banks
+            for _ in 0..2 {
+                assert_eq!(cartridge.read(0x8000), Some(0x00), "mapper {}", mapper);
+                assert_eq!(cartridge.read(0xC100), Some(0x01), "mapper {}", mapper);
+                assert_eq!(cartridge.read(0xFFFF), Some(0x1F), "mapper {}", mapper);
+                cartridge.write(0x8000, 0xFF);
+                cartridge.write(0xE000, 0xFF);
+            }
+        }
+    }
+
+    #[test]
+    fn small_chr_rom() {
+        for mapper in [0, 1, 2, 3, 4, 7] {
+            // NES 2.0 exponent-multiplier size, 2^12 * 1
+            let mut image = rom(mapper, 1, 0, 0);
+            image[5] = 12 << 2;
+            image[7] |= 0b0000_1000;
+            image[9] = 0xF0;
+            image.extend((0..4 * 1024).map(|i| (i >> 8) as u8));
+            let mut cartridge = Cartridge::from_bytes(&image).unwrap();
+            assert_eq!(cartridge.header().chr_rom_size, 4 * 1024);
+
+            // Mirrored across both pattern tables
+            assert_eq!(cartridge.ppu_read(0x0000), 0x00, "mapper {}", mapper);
+            assert_eq!(cartridge.ppu_read(0x0700), 0x07, "mapper {}", mapper);
+            assert_eq!(cartridge.ppu_read(0x1100), 0x01, "mapper {}", mapper);
+
+            // Whatever the banks, every address reads from the 4 KiB
+            cartridge.write(0x8000, 0xFF);
+            cartridge.write(0x8001, 0xFF);
+            cartridge.write(0xA000, 0xFF);
+            for address in 0x0000..0x2000 {
+                assert!(cartridge.ppu_read(address) < 0x10, "mapper {}", mapper);
+            }
+        }
+    }
+
+    /// Whether writing 1 to $8000, where the ROM drives 0, is lost to a bus conflict.
+    fn has_bus_conflicts(mapper: u8, submapper: Option<u8>) -> bool {
+        let mut image = rom(mapper, 4, 2, 0);
+        // CHR bank 1, for CNROM
+        image[16 + 4 * 16 * 1024 + 8 * 1024] = 0xFF;
+        if let Some(submapper) = submapper {
+            image[7] |= 0b0000_1000;
+            image[8] = submapper << 4;
+        }
+        let mut cartridge = Cartridge::from_bytes(&image).unwrap();
+        assert_eq!(cartridge.read(0x8000), Some(0));
+        cartridge.write(0x8000, 0x01);
+        match mapper {
+            3 => cartridge.ppu_read(0x0000) == 0x00,
+            _ => cartridge.read(0x8000) == Some(0),
+        }
+    }
+
+    #[test]
+    fn bus_conflicts() {
+        // iNES and NES 2.0 submapper 0 follow the common boards: UNROM, CNROM and AxROM
+        for submapper in [None, Some(0)] {
+            assert!(has_bus_conflicts(2, submapper));
+            assert!(has_bus_conflicts(3, submapper));
+            assert!(!has_bus_conflicts(7, submapper));
+        }
+        // Submapper 1 has none, e.g. ANROM, 2 has them, e.g. AOROM
+        for mapper in [2, 3, 7] {
+            assert!(!has_bus_conflicts(mapper, Some(1)), "mapper {}", mapper);
+            assert!(has_bus_conflicts(mapper, Some(2)), "mapper {}", mapper);
+        }
+
+        // The written value is ANDed with the ROM byte
+        let mut cartridge = Cartridge::from_bytes(&rom(2, 8, 0, 0)).unwrap();
+        // The ROM drives 7, then 5
+        cartridge.write(0xC000, 0x05);
+        assert_eq!(cartridge.read(0x8000), Some(5));
+        cartridge.write(0x8000, 0x06);
+        assert_eq!(cartridge.read(0x8000), Some(4));
+    }
+
+    #[test]
+    fn trainer() {
+        // LDA #$42, STA $00, JMP $7004
+        let mut trainer = vec![0xA9, 0x42, 0x85, 0x00, 0x4C, 0x04, 0x70];
+        trainer.resize(512, 0xEA);
+
+        let mut image = rom(0, 1, 1, 0b0000_0100);
+        image.splice(16..16, trainer.iter().copied());
+        // Reset vector at $7000
+        image[16 + 512 + 0x3FFC] = 0x00;
+        image[16 + 512 + 0x3FFD] = 0x70;
+
+        let cartridge = Cartridge::from_bytes(&image).unwrap();
+        assert_eq!(cartridge.trainer(), Some(&trainer[..]));
+        assert_eq!(&cartridge.prg_ram()[0x1000..0x1200], &trainer[..]);
+        // The PRG ROM starts after the trainer
+        assert_eq!(cartridge.read(0x8000), Some(0));
+
+        let mut cpu = CPU::new(SystemBus::new(cartridge));
+        assert_eq!(cpu.pc, 0x7000);
+        for _ in 0..20 {
+            cpu.clock();
+        }
+        assert_eq!(cpu.bus.read(0x0000), 0x42);
+
+        assert!(matches!(
+            Cartridge::from_bytes(&image[..16 + 256]).unwrap_err(),
+            CartridgeError::TruncatedTrainer
+        ));
+    }
+
+    /// A UNIF image made of `chunks`.
+    fn unif(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut unif = b"UNIF".to_vec();
+        unif.extend_from_slice(&7u32.to_le_bytes());
+        unif.resize(32, 0);
+        for (id, data) in chunks {
+            unif.extend_from_slice(*id);
+            unif.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            unif.extend_from_slice(data);
+        }
+        unif
+    }
+
+    #[test]
+    fn unif_loader() {
+        use crate::header::{HeaderFormat, Timing};
+
+        let bank = |n| vec![n; 16 * 1024];
+        let image = unif(&[
+            (b"MAPR", b"NES-UNROM\0".to_vec()),
+            (b"NAME", b"Test\0".to_vec()),
+            // Chunks are ordered by their number, not by their position
+            (b"PRG1", bank(1)),
+            (b"PRG0", bank(0)),
+            (b"MIRR", vec![1]),
+            (b"BATR", vec![1]),
+            (b"TVCI", vec![1]),
+        ]);
+        let mut cartridge = Cartridge::from_bytes(&image).unwrap();
+        let ines = Cartridge::from_bytes(&rom(2, 2, 0, 0b0000_0011)).unwrap();
+
+        let header = cartridge.header();
+        assert_eq!(header.format, HeaderFormat::Unif);
+        assert_eq!(header.timing, Timing::Pal);
+        assert_eq!(header.mapper, ines.header().mapper);
+        assert_eq!(header.prg_nvram_size(), ines.header().prg_nvram_size());
+        assert_eq!(cartridge.mirroring(), ines.mirroring());
+        assert!(cartridge.has_battery());
+        assert_eq!(cartridge.prg_ram().len(), ines.prg_ram().len());
+
+        // Same board as the iNES mapper 2
+        assert_eq!(cartridge.read(0x8000), Some(0));
+        assert_eq!(cartridge.read(0xC000), Some(1));
+        cartridge.write(0xC000, 1);
+        assert_eq!(cartridge.read(0x8000), Some(1));
+        // CHR RAM without CHR chunks
+        cartridge.ppu_write(0x0010, 0xAB);
+        assert_eq!(cartridge.ppu_read(0x0010), 0xAB);
+
+        // AxROM boards with and without bus conflicts, 32 KiB banks 0 and 1
+        let axrom = |board: &[u8]| {
+            let prg = [vec![0; 32 * 1024], vec![1; 32 * 1024]].concat();
+            let image = unif(&[(b"MAPR", board.to_vec()), (b"PRG0", prg)]);
+            let mut cartridge = Cartridge::from_bytes(&image).unwrap();
+            cartridge.write(0x8000, 0x01);
+            cartridge.read(0x8000)
+        };
+        assert_eq!(axrom(b"NES-ANROM\0"), Some(1));
+        // The ROM drives 0
+        assert_eq!(axrom(b"NES-AOROM\0"), Some(0));
+
+        // An 8 KiB PRG0 is mirrored like a small iNES PRG ROM
+        for board in [
+            "NES-NROM",
+            "NES-SLROM",
+            "NES-UNROM",
+            "NES-CNROM",
+            "NES-TLROM",
+            "NES-ANROM",
+        ] {
+            let prg = (0..8 * 1024).map(|i| (i >> 8) as u8).collect();
+            let image = unif(&[(b"MAPR", board.as_bytes().to_vec()), (b"PRG0", prg)]);
+            let mut cartridge = Cartridge::from_bytes(&image).unwrap();
+            assert_eq!(cartridge.read(0x8000), Some(0x00), "{}", board);
+            assert_eq!(cartridge.read(0xFFFF), Some(0x1F), "{}", board);
+            cartridge.write(0xC000, 0xFF);
+            assert_eq!(cartridge.read(0xA100), Some(0x01), "{}", board);
+        }
+
+        let error =
+            |chunks: &[(&[u8; 4], Vec<u8>)]| Cartridge::from_bytes(&unif(chunks)).unwrap_err();
+        assert_eq!(
+            error(&[
+                (b"MAPR", b"UNL-Sachen-8259A\0".to_vec()),
+                (b"PRG0", bank(0))
+            ])
+            .to_string(),
+            "Unsupported board UNL-Sachen-8259A"
+        );
+        assert!(matches!(
+            error(&[(b"PRG0", bank(0))]),
+            CartridgeError::MissingChunk("MAPR")
+        ));
+        assert!(matches!(
+            error(&[(b"MAPR", b"NES-NROM-128\0".to_vec())]),
+            CartridgeError::MissingPrgRom
+        ));
+        let mut truncated = unif(&[(b"MAPR", b"NES-NROM-128\0".to_vec()), (b"PRG0", bank(0))]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(matches!(
+            Cartridge::from_bytes(&truncated).unwrap_err(),
+            CartridgeError::TruncatedChunk(id) if id == "PRG0"
+        ));
+    }
+
+    #[test]
+    fn archives() {
+        use std::io::{Cursor, Write};
+
+        use flate2::write::GzEncoder;
+        use zip::write::SimpleFileOptions;
+        use zip::ZipWriter;
+
+        use crate::archive::ArchiveError;
+
+        let zip = |files: &[(&str, Vec<u8>)]| {
+            let mut zip = ZipWriter::new(Cursor::new(vec![]));
+            for (name, bytes) in files {
+                zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+                zip.write_all(bytes).unwrap();
+            }
+            zip.finish().unwrap().into_inner()
+        };
+        let path = std::env::temp_dir().join("nesmulator_archive_test.zip");
+        let load = |archive: Vec<u8>| {
+            fs::write(&path, archive).unwrap();
+            Cartridge::from_file(&path)
+        };
+
+        let cartridge = load(zip(&[
+            ("readme.txt", b"Not a ROM".to_vec()),
+            ("Game (USA).NES", rom(2, 4, 0, 0)),
+        ]))
+        .unwrap();
+        assert_eq!(cartridge.header().mapper, 2);
+
+        let mut gzip = GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(&rom(7, 2, 0, 0)).unwrap();
+        let cartridge = load(gzip.finish().unwrap()).unwrap();
+        assert_eq!(cartridge.header().mapper, 7);
+
+        // Several ROMs, the caller has to choose
+        let archive = zip(&[
+            ("Game (USA).nes", rom(2, 4, 0, 0)),
+            ("Game (Europe).nes", rom(3, 2, 4, 0)),
+        ]);
+        match load(archive.clone()) {
+            Err(CartridgeError::Archive(ArchiveError::AmbiguousRom { candidates })) => {
+                assert_eq!(candidates, ["Game (USA).nes", "Game (Europe).nes"])
+            }
+            result => panic!("{:?}", result),
+        }
+        fs::write(&path, &archive).unwrap();
+        let cartridge = Cartridge::from_file_with(&path, |candidates| {
+            candidates.iter().position(|name| name.contains("Europe"))
+        })
+        .unwrap();
+        assert_eq!(cartridge.header().mapper, 3);
+
+        assert!(matches!(
+            load(zip(&[("readme.txt", vec![])])),
+            Err(CartridgeError::Archive(ArchiveError::NoRom))
+        ));
+        assert!(matches!(
+            load(archive[..archive.len() / 2].to_vec()),
+            Err(CartridgeError::Archive(ArchiveError::Zip(_)))
+        ));
+        assert!(matches!(
+            load(vec![0x1F, 0x8B, 0x08, 0x00, 0xFF]),
+            Err(CartridgeError::Archive(ArchiveError::Gzip(_)))
+        ));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn errors() {
+        let error = |bytes: &[u8]| Cartridge::from_bytes(bytes).unwrap_err();
+
+        assert!(matches!(error(b"NES"), CartridgeError::TruncatedHeader));
+        assert!(matches!(error(b"PK\x03\x04"), CartridgeError::BadMagic));
+        assert!(matches!(error(&[0; 32]), CartridgeError::BadMagic));
+
+        let rom = rom(0, 2, 1, 0);
+        assert!(matches!(
+            error(&rom[..16 + 0x4000]),
+            CartridgeError::TruncatedPrgRom {
+                expected: 0x8000,
+                actual: 0x4000
+            }
+        ));
+        assert!(matches!(
+            error(&rom[..rom.len() - 1]),
+            CartridgeError::TruncatedChrRom { .. }
+        ));
+
+        let mut mmc5 = rom.clone();
+        mmc5[6] |= 0x50;
+        let message = error(&mmc5).to_string();
+        assert_eq!(message, "Unsupported mapper 5 (MMC5)");
+
+        // NES 2.0 exponent-multiplier notation with an absurd exponent
+        let mut nes2 = rom.clone();
+        nes2[7] |= 0b0000_1000;
+        nes2[9] = 0x0F;
+        nes2[4] = 0xFF;
+        assert!(matches!(
+            error(&nes2),
+            CartridgeError::InvalidNes2Field { value: 0xFF, .. }
+        ));
+
+        let mut no_prg_rom = rom.clone();
+        no_prg_rom[4] = 0;
+        assert!(matches!(error(&no_prg_rom), CartridgeError::MissingPrgRom));
+
+        assert!(matches!(
+            Cartridge::from_file("misc/missing.nes").unwrap_err(),
+            CartridgeError::Io(_)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod header {
+    use crate::cartridge::Mirroring;
+    use crate::header::{ConsoleType, HeaderFormat, RomHeader, Timing};
+
+    #[test]
+    fn ines() {
+        let header = RomHeader::parse(b"NES\x1A\x02\x01\x13\x40\x02\x01\0\0\0\0\0\0").unwrap();
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.prg_rom_size, 32 * 1024);
+        assert_eq!(header.chr_rom_size, 8 * 1024);
+        assert_eq!(header.mapper, 0x41);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert!(header.has_battery);
+        assert_eq!(header.prg_ram_size(), 0);
+        assert_eq!(header.prg_nvram_size(), 16 * 1024);
+        assert_eq!(header.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn archaic_ines() {
+        let header = RomHeader::parse(b"NES\x1A\x02\x00\x10DiskDude!").unwrap();
+        assert_eq!(header.format, HeaderFormat::ArchaicINes);
+        // The "D" of DiskDude! must not end up in the mapper number
+        assert_eq!(header.mapper, 1);
+        assert_eq!(header.chr_ram_size(), 8 * 1024);
+        assert_eq!(header.console_type, ConsoleType::Nes);
+    }
+
+    #[test]
+    fn nes2() {
+        let header = RomHeader::parse(&[
+            b'N', b'E', b'S', 0x1A, 0x10, 0x00, 0x4E, 0x19, 0x52, 0x00, 0x97, 0x07, 0x03, 0x25,
+            0x02, 0x23,
+        ])
+        .unwrap();
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!(header.prg_rom_size, 256 * 1024);
+        assert_eq!(header.mapper, 0x214);
+        assert_eq!(header.submapper, 5);
+        assert_eq!(header.mirroring, Mirroring::FourScreen);
+        assert!(header.has_trainer);
+        assert_eq!(header.prg_ram_size(), 8 * 1024);
+        assert_eq!(header.prg_nvram_size(), 32 * 1024);
+        assert_eq!(header.chr_ram_size(), 8 * 1024);
+        assert_eq!(header.chr_nvram_size(), 0);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(
+            header.console_type,
+            ConsoleType::VsSystem {
+                ppu_type: 5,
+                hardware_type: 2
+            }
+        );
+        assert_eq!(header.misc_rom_count, 2);
+        assert_eq!(header.default_expansion_device, 0x23);
+    }
+
+    #[test]
+    fn nes2_exponent_multiplier() {
+        let mut bytes = *b"NES\x1A\x00\x00\x00\x08\x00\x0F\0\0\0\0\0\0";
+        // 2^10 * 3
+        bytes[4] = 0b0010_1001;
+        let header = RomHeader::parse(&bytes).unwrap();
+        assert_eq!(header.prg_rom_size, 3 * 1024);
+    }
+}
+
+#[cfg(test)]
+mod database {
+    use std::{fs, io};
+
+    use crate::cartridge::{Cartridge, Mirroring};
+    use crate::checksum::{crc32, sha1};
+    use crate::database::{DatabaseError, HeaderField, RomDatabase};
+    use crate::header::{HeaderFormat, Timing};
+    use crate::tests::cartridge::rom;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+
+        let hex = |digest: [u8; 20]| -> String {
+            digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+        };
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // Two blocks of padding
+        assert_eq!(
+            hex(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn builtin() {
+        assert!(!RomDatabase::builtin().is_empty());
+
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        assert_eq!(cartridge.crc32(), 0x158B_0388);
+        // nestest is in the database and its header is right
+        assert_eq!(cartridge.header().format, HeaderFormat::Nes2);
+        assert!(cartridge.corrected_fields().is_empty());
+    }
+
+    #[test]
+    fn corrections() {
+        // Actually a CNROM game with vertical mirroring and a battery, for PAL consoles
+        let image = rom(0, 2, 1, 0);
+        let rom = &image[16..];
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db>
+    <!-- Test (Europe) -->
+    <game>
+        <rom size="{}" crc32="{:08X}"/>
+        <prgnvram size="8192"/>
+        <pcb mapper="3" submapper="0" mirroring="V" battery="1"/>
+        <console type="0" region="1"/>
+    </game>
+</nes20db>"#,
+            rom.len(),
+            crc32(rom)
+        );
+        let database = RomDatabase::parse(&xml).unwrap();
+        assert_eq!(database.len(), 1);
+
+        let cartridge = Cartridge::from_bytes_with_database(&image, &database).unwrap();
+        assert_eq!(
+            cartridge.corrected_fields(),
+            [
+                HeaderField::Mapper,
+                HeaderField::Mirroring,
+                HeaderField::Battery,
+                HeaderField::PrgRam,
+                HeaderField::PrgNvram,
+                HeaderField::Timing
+            ]
+        );
+        let header = cartridge.header();
+        assert_eq!(header.mapper, 3);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert_eq!(header.timing, Timing::Pal);
+        assert_eq!(header.prg_ram_size(), 0);
+        assert_eq!(header.prg_nvram_size(), 8192);
+        assert!(cartridge.has_battery());
+
+        // A wrong SHA-1 doesn't match
+        let xml = xml.replace(
+            "/>\n        <prgnvram",
+            &format!(" sha1=\"{}\"/>\n        <prgnvram", "00".repeat(20)),
+        );
+        let database = RomDatabase::parse(&xml).unwrap();
+        let cartridge = Cartridge::from_bytes_with_database(&image, &database).unwrap();
+        assert!(cartridge.corrected_fields().is_empty());
+        assert_eq!(cartridge.header().mapper, 0);
+    }
+
+    #[test]
+    fn from_file() {
+        let database = RomDatabase::from_file("misc/nes20db.xml").unwrap();
+        assert_eq!(database.len(), RomDatabase::builtin().len());
+
+        // nestest with a bad header: MMC1, vertical mirroring and a battery
+        let mut image = fs::read("misc/nestest.nes").unwrap();
+        image[6] = 0x13;
+        let path = std::env::temp_dir().join("nesmulator_database_test.nes");
+        fs::write(&path, &image).unwrap();
+        let cartridge = Cartridge::from_file_with_database(&path, &database).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            cartridge.corrected_fields(),
+            [
+                HeaderField::Mapper,
+                HeaderField::Mirroring,
+                HeaderField::Battery,
+                // The battery made the iNES PRG RAM non-volatile
+                HeaderField::PrgRam,
+                HeaderField::PrgNvram
+            ]
+        );
+        let header = cartridge.header();
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!(header.mapper, 0);
+        assert_eq!(header.mirroring, Mirroring::Horizontal);
+        assert!(!cartridge.has_battery());
+
+        let path = std::env::temp_dir().join("nesmulator_database_test.xml");
+        fs::write(&path, "<game>").unwrap();
+        let error = RomDatabase::from_file(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(RomDatabase::from_file("misc/missing.xml").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let error = |xml| RomDatabase::parse(xml).unwrap_err();
+        assert_eq!(
+            error("<game>\n<pcb mapper=\"1\"/>\n</game>"),
+            DatabaseError {
+                line: 2,
+                message: "missing attribute"
+            }
+        );
+        assert_eq!(error("<game>\n</game>").message, "game without <rom>");
+        assert_eq!(error("<game><game>").message, "unexpected <game>");
+        assert_eq!(error("<game>").message, "unterminated <game>");
+        assert_eq!(
+            error("<game><rom crc32=\"XYZ\"/>").message,
+            "invalid attribute value"
+        );
+        assert_eq!(
+            error("<game><rom crc32=1/>").message,
+            "unquoted attribute value"
+        );
+    }
+}
+
+#[cfg(test)]
+mod mapper {
+    use crate::bus::SystemBus;
+    use crate::cartridge::{Cartridge, Mirroring};
+    use crate::cpu::CPU;
+    use crate::mapper::{Mapper, Mapper1, Mapper2, Mapper3, Mapper4, Mapper7, Mmc3Revision};
+
+    /// Writes a 5 bit MMC1 register through the serial port.
+    fn mmc1_write(mapper: &mut Mapper1, address: u16, value: u8) {
+        for i in 0..5 {
+            mapper.write(address, (value >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn mmc1_prg_banks() {
+        let mut mapper = Mapper1::new(256 * 1024, 128 * 1024, 8 * 1024);
+        // Power on: PRG mode 3, last bank fixed at $C000
+        assert_eq!(mapper.prg_address(0xC000), 15 * 0x4000);
+
+        mmc1_write(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.prg_address(0x8000), 5 * 0x4000);
+        assert_eq!(mapper.prg_address(0xFFFF), 15 * 0x4000 + 0x3FFF);
+
+        // PRG mode 2, first bank fixed at $8000
+        mmc1_write(&mut mapper, 0x8000, 0b01000);
+        assert_eq!(mapper.prg_address(0x8000), 0);
+        assert_eq!(mapper.prg_address(0xC000), 5 * 0x4000);
+
+        // 32 KiB mode
+        mmc1_write(&mut mapper, 0x8000, 0b00000);
+        assert_eq!(mapper.prg_address(0x8000), 4 * 0x4000);
+        assert_eq!(mapper.prg_address(0xC000), 5 * 0x4000);
+
+        // Writing with bit 7 set resets the shift register and goes back to PRG mode 3
+        mapper.write(0x8000, 1);
+        mapper.write(0x8000, 0x80);
+        assert_eq!(mapper.prg_address(0xC000), 15 * 0x4000);
+    }
+
+    #[test]
+    fn mmc1_ignores_consecutive_writes() {
+        let mut rom = crate::tests::cartridge::rom(1, 4, 0, 0);
+        // The program lives in the last bank, fixed at $C000
+        let last_bank = 16 + 3 * 0x4000;
+        let program = [
+            0xA9, 0x01, // LDA #$01
+            0xEE, 0xF0, 0xFF, // INC $FFF0
+            0x8D, 0x00, 0xE0, // STA $E000
+            0x4A, // LSR A
+            0x8D, 0x00, 0xE0, // STA $E000
+            0x8D, 0x00, 0xE0, // STA $E000
+            0x8D, 0x00, 0xE0, // STA $E000
+            0x8D, 0x00, 0xE0, // STA $E000
+        ];
+        rom[last_bank..last_bank + program.len()].copy_from_slice(&program);
+        rom[last_bank + 0x3FF0] = 0xFF;
+        // Reset vector
+        rom[last_bank + 0x3FFC] = 0x00;
+        rom[last_bank + 0x3FFD] = 0xC0;
+
+        let cartridge = Cartridge::from_bytes(&rom).unwrap();
+        let mut cpu = CPU::new(SystemBus::new(cartridge));
+        for _ in 0..8 {
+            cpu.step();
+        }
+        // INC writes $FF, which resets the shift register, then $00 on the next cycle
+        // which is ignored. Otherwise it would shift a 0 and select bank 2.
+        assert_eq!(cpu.bus.cartridge.read(0x8000), Some(1));
+    }
+
+    #[test]
+    fn mmc1_chr_banks_and_mirroring() {
+        let mut mapper = Mapper1::new(128 * 1024, 128 * 1024, 8 * 1024);
+        mmc1_write(&mut mapper, 0xA000, 3);
+        mmc1_write(&mut mapper, 0xC000, 7);
+        // 8 KiB mode ignores the low bit and the second register
+        assert_eq!(mapper.chr_address(0x0000), 2 * 0x1000);
+        assert_eq!(mapper.chr_address(0x1000), 3 * 0x1000);
+
+        mmc1_write(&mut mapper, 0x8000, 0b11110);
+        assert_eq!(mapper.chr_address(0x0000), 3 * 0x1000);
+        assert_eq!(mapper.chr_address(0x1FFF), 7 * 0x1000 + 0xFFF);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Vertical));
+    }
+
+    #[test]
+    fn mmc1_board_variants() {
+        // SUROM, 512 KiB of PRG ROM
+        let mut mapper = Mapper1::new(512 * 1024, 8 * 1024, 8 * 1024);
+        assert_eq!(mapper.prg_address(0xC000), 15 * 0x4000);
+        mmc1_write(&mut mapper, 0xA000, 0b10000);
+        assert_eq!(mapper.prg_address(0xC000), 31 * 0x4000);
+
+        // SOROM, 16 KiB of PRG RAM
+        let mut mapper = Mapper1::new(256 * 1024, 8 * 1024, 16 * 1024);
+        mmc1_write(&mut mapper, 0xA000, 0b01000);
+        assert_eq!(mapper.prg_ram_address(0x6000), Some(0x2000));
+        mmc1_write(&mut mapper, 0xE000, 0b10000);
+        assert_eq!(mapper.prg_ram_address(0x6000), None);
+    }
+
+    #[test]
+    fn discrete_logic_mappers() {
+        let mut uxrom = Mapper2::new(128 * 1024, false);
+        uxrom.write(0x8000, 3);
+        assert_eq!(uxrom.prg_address(0x8000), 3 * 0x4000);
+        assert_eq!(uxrom.prg_address(0xC000), 7 * 0x4000);
+
+        let mut cnrom = Mapper3::new(16 * 1024, 32 * 1024, false);
+        cnrom.write(0x8000, 2);
+        assert_eq!(cnrom.prg_address(0xC000), 0);
+        assert_eq!(cnrom.chr_address(0x0010), 2 * 0x2000 + 0x10);
+
+        let mut axrom = Mapper7::new(256 * 1024, false);
+        assert_eq!(axrom.mirroring(), Some(Mirroring::SingleScreenA));
+        axrom.write(0x8000, 0b0001_0101);
+        assert_eq!(axrom.prg_address(0x8000), 5 * 0x8000);
+        assert_eq!(axrom.mirroring(), Some(Mirroring::SingleScreenB));
+    }
+
+    /// A12 low during the background fetches, then high for the sprite fetches.
+    fn mmc3_scanline(mapper: &mut Mapper4) {
+        mapper.ppu_address(0x0000);
+        for _ in 0..80 {
+            mapper.cpu_clock();
+        }
+        mapper.ppu_address(0x1000);
+    }
+
+    #[test]
+    fn mmc3_banks() {
+        let mut mapper = Mapper4::new(128 * 1024, 256 * 1024, Mmc3Revision::Sharp);
+        for (register, bank) in [(0, 8), (1, 10), (2, 20), (5, 23), (6, 3), (7, 4)] {
+            mapper.write(0x8000, register);
+            mapper.write(0x8001, bank);
+        }
+        assert_eq!(mapper.prg_address(0x8000), 3 * 0x2000);
+        assert_eq!(mapper.prg_address(0xA000), 4 * 0x2000);
+        assert_eq!(mapper.prg_address(0xC000), 14 * 0x2000);
+        assert_eq!(mapper.prg_address(0xE000), 15 * 0x2000);
+        assert_eq!(mapper.chr_address(0x0400), 9 * 0x400);
+        assert_eq!(mapper.chr_address(0x1000), 20 * 0x400);
+        assert_eq!(mapper.chr_address(0x1C00), 23 * 0x400);
+
+        // PRG mode 1 and CHR inversion
+        mapper.write(0x8000, 0b1100_0000);
+        assert_eq!(mapper.prg_address(0x8000), 14 * 0x2000);
+        assert_eq!(mapper.prg_address(0xC000), 3 * 0x2000);
+        assert_eq!(mapper.chr_address(0x0000), 20 * 0x400);
+        assert_eq!(mapper.chr_address(0x1800), 10 * 0x400);
+
+        mapper.write(0xA000, 1);
+        assert_eq!(mapper.mirroring(), Some(Mirroring::Horizontal));
+        mapper.write(0xA001, 0b1100_0000);
+        assert!(!mapper.is_prg_ram_writable());
+        mapper.write(0xA001, 0);
+        assert_eq!(mapper.prg_ram_address(0x6000), None);
+    }
+
+    #[test]
+    fn mmc3_scanline_irq() {
+        let mut mapper = Mapper4::new(128 * 1024, 256 * 1024, Mmc3Revision::Sharp);
+        mapper.write(0xC000, 2);
+        mapper.write(0xC001, 0);
+        mapper.write(0xE001, 0);
+
+        mmc3_scanline(&mut mapper);
+        mmc3_scanline(&mut mapper);
+        assert!(!mapper.irq());
+        mmc3_scanline(&mut mapper);
+        assert!(mapper.irq());
+        mapper.write(0xE000, 0);
+        assert!(!mapper.irq());
+
+        // Rising edges too close to each other are filtered out
+        mapper.write(0xE001, 0);
+        for _ in 0..10 {
+            mapper.ppu_address(0x0000);
+            mapper.cpu_clock();
+            mapper.ppu_address(0x1000);
+        }
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn mmc3_revisions() {
+        for &(revision, expected) in &[
+            (Mmc3Revision::Sharp, [true, true, true]),
+            (Mmc3Revision::Nec, [true, false, false]),
+        ] {
+            let mut mapper = Mapper4::new(128 * 1024, 256 * 1024, revision);
+            mapper.write(0xC000, 0);
+            mapper.write(0xC001, 0);
+            mapper.write(0xE001, 0);
+            for &irq in &expected {
+                mmc3_scanline(&mut mapper);
+                assert_eq!(mapper.irq(), irq, "{:?}", revision);
+                mapper.write(0xE000, 0);
+                mapper.write(0xE001, 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod nes {
+    use std::fs;
+
+    use crate::breakpoints::{Access, Breakpoint, BreakpointKind, Condition};
+    use crate::bus::Bus;
+    use crate::cartridge::Cartridge;
+    use crate::nes::{Nes, StopReason, MASTER_CYCLES_PER_CPU_CYCLE};
+    use crate::ppu::VBLANK_SCANLINE;
+    use crate::tests::cartridge::rom;
+
+    fn nestest() -> Nes {
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut nes = Nes::new(cartridge);
+        nes.cpu.pc = 0xC000;
+        nes
+    }
+
+    #[test]
+    fn steps() {
+        let mut nes = nestest();
+        // Power on runs the 7 cycles of the reset sequence
+        assert_eq!(nes.cpu_cycles(), 7);
+        assert_eq!(nes.master_cycles(), 7 * MASTER_CYCLES_PER_CPU_CYCLE);
+
+        // JMP $C5F5
+        assert_eq!(nes.step_cycle(), StopReason::Done);
+        assert_eq!(nes.cpu_cycles(), 8);
+        assert_eq!(nes.step_instruction(), StopReason::Done);
+        assert_eq!(nes.cpu.pc, 0xC5F5);
+        assert_eq!(nes.cpu_cycles(), 10);
+
+        // LDX #$00, then the first cycle of STX $00
+        assert_eq!(nes.run_for_cycles(3), StopReason::Done);
+        assert_eq!(nes.cpu_cycles(), 13);
+        assert!(!nes.cpu.at_instruction_boundary());
+        nes.step_instruction();
+        assert_eq!(nes.cpu.pc, 0xC5F9);
+    }
+
+    #[test]
+    fn run_until() {
+        let mut nes = nestest();
+        assert_eq!(
+            nes.run_until(|nes| nes.cpu.pc == 0xC66E),
+            StopReason::Condition
+        );
+        assert_eq!(nes.cpu_cycles(), 26554);
+
+        // The condition is checked after running at least one instruction
+        assert_eq!(nes.run_until(|_| true), StopReason::Condition);
+        assert_ne!(nes.cpu.pc, 0xC66E);
+    }
+
+    #[test]
+    fn run_frame() {
+        // From the reset vector nestest shows its menu and waits for NMIs
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut nes = Nes::new(cartridge);
+        assert_eq!(nes.run_frame(), StopReason::Done);
+        assert_eq!(nes.cpu.bus.ppu.scanline, VBLANK_SCANLINE);
+
+        // 341 * 262 dots, 3 per CPU cycle
+        let start = nes.cpu_cycles();
+        nes.run_frame();
+        let cycles = nes.cpu_cycles() - start;
+        assert!((29780..=29781).contains(&cycles), "{}", cycles);
+    }
+
+    #[test]
+    fn stop_reasons() {
+        let mut nes = nestest();
+        // JAM
+        nes.cpu.bus.ram[0x0300] = 0x02;
+        nes.cpu.pc = 0x0300;
+        assert_eq!(
+            nes.run_until(|_| false),
+            StopReason::Jammed {
+                pc: 0x0300,
+                opcode: 0x02
+            }
+        );
+        assert_eq!(
+            nes.run_for_cycles(10),
+            StopReason::Jammed {
+                pc: 0x0300,
+                opcode: 0x02
+            }
+        );
+
+        // BRK
+        nes.reset();
+        nes.cpu.break_on_brk = true;
+        nes.cpu.bus.ram[0x0300] = 0x00;
+        nes.cpu.pc = 0x0300;
+        assert_eq!(nes.run_frame(), StopReason::Break { pc: 0x0300 });
+        // Cycle by cycle, it stops after the opcode fetch
+        assert_eq!(nes.cpu.pc, 0x0301);
+        assert_eq!(nes.step_instruction(), StopReason::Done);
+        assert_eq!(nes.cpu.pc, nes.cpu.peek_u16(0xFFFE));
+    }
+
+    #[test]
+    fn battery_save() {
+        let rom_path = std::env::temp_dir().join("nesmulator_nes_save_test.nes");
+        let save_path = rom_path.with_extension("sav");
+        let _ = fs::remove_file(&save_path);
+        fs::write(&rom_path, rom(0, 1, 1, 0b0000_0010)).unwrap();
+
+        let mut nes = Nes::new(Cartridge::from_file(&rom_path).unwrap());
+        // LDA #$42; STA $6000
+        nes.cpu.bus.ram[0x0300..0x0305].copy_from_slice(&[0xA9, 0x42, 0x8D, 0x00, 0x60]);
+        nes.cpu.pc = 0x0300;
+        nes.step_instruction();
+        nes.step_instruction();
+        nes.save().unwrap();
+        drop(nes);
+
+        let nes = Nes::new(Cartridge::from_file(&rom_path).unwrap());
+        assert_eq!(nes.cpu.bus.peek(0x6000), 0x42);
+
+        fs::remove_file(&rom_path).unwrap();
+        fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn ppu_watchpoint() {
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut nes = Nes::new(cartridge);
+        // nestest polls PPUSTATUS until VBlank starts
+        let mut breakpoint = Breakpoint::new(BreakpointKind::Read, 0x2002..=0x2002);
+        breakpoint.condition = Some(Condition::parse("value >= $80").unwrap());
+        let id = nes.cpu.breakpoints.add(breakpoint);
+        match nes.run_until(|_| false) {
+            StopReason::Breakpoint(hit) => {
+                assert_eq!(hit.id, id);
+                assert_eq!(hit.access, Access::Read);
+                assert_eq!(hit.address, 0x2002);
+                assert_eq!(nes.cpu.bus.ppu.scanline, VBLANK_SCANLINE);
+            }
+            reason => panic!("{:?}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod breakpoints {
+    use crate::breakpoints::{parse_number, Condition, ConditionError, Registers};
+
+    fn registers() -> Registers {
+        Registers {
+            a: 0x10,
+            x: 3,
+            y: 0,
+            s: 0xFD,
+            pc: 0xC000,
+            // N and C
+            p: 0b1010_0001,
+            value: 0xFF,
+        }
+    }
+
+    fn evaluate(source: &str) -> bool {
+        Condition::parse(source).unwrap().evaluate(&registers())
+    }
+
+    #[test]
+    fn conditions() {
+        assert!(evaluate("a == $10"));
+        assert!(evaluate("A == 0x10 && X >= 3"));
+        assert!(evaluate("pc==49152"));
+        assert!(evaluate("c && n && !z"));
+        assert!(evaluate("!(y != 0) || v"));
+        assert!(evaluate("sp > $F0 && value == 255"));
+        assert!(!evaluate("a < 16"));
+        assert!(!evaluate("x == 3 && (z || i)"));
+        assert!(!evaluate("y"));
+        // && binds tighter than ||
+        assert!(evaluate("a == 0 && x == 0 || c"));
+
+        assert_eq!(
+            Condition::parse("  a == $10 ").unwrap().to_string(),
+            "a == $10"
+        );
+        assert_eq!(parse_number("$C000"), Some(0xC000));
+        assert_eq!(parse_number("0x10000"), None);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source| Condition::parse(source).unwrap_err();
+        assert_eq!(
+            error("a == "),
+            ConditionError {
+                position: 5,
+                message: "expected a number or a register"
+            }
+        );
+        assert_eq!(
+            error("q == 1"),
+            ConditionError {
+                position: 0,
+                message: "unknown register or invalid number"
+            }
+        );
+        assert_eq!(error("(a == 1").message, "expected ')'");
+        assert_eq!(error("a == 1 b").position, 7);
+        assert_eq!(
+            error("a = 1").to_string(),
+            "Invalid condition, column 3: unexpected character"
+        );
+    }
+}
+
+#[cfg(test)]
+mod debugger {
+    use crate::cartridge::Cartridge;
+    use crate::debugger::{CommandError, Debugger, Frame, FrameKind};
+    use crate::nes::Nes;
+
+    /// JSR $0310; LDA #$01; JAM, calling JSR $0320; RTS, calling INX; RTS
+    fn program() -> Debugger {
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut nes = Nes::new(cartridge);
+        let ram = &mut nes.cpu.bus.ram;
+        ram[0x0300..0x0306].copy_from_slice(&[0x20, 0x10, 0x03, 0xA9, 0x01, 0x02]);
+        ram[0x0310..0x0314].copy_from_slice(&[0x20, 0x20, 0x03, 0x60]);
+        ram[0x0320..0x0322].copy_from_slice(&[0xE8, 0x60]);
+        nes.cpu.pc = 0x0300;
+        Debugger::new(nes)
+    }
+
+    #[test]
+    fn disassembler() {
+        let debugger = program();
+        let cpu = &debugger.nes.cpu;
+        assert_eq!(
+            cpu.disassemble(0x0300).to_string(),
+            "0300  20 10 03  JSR $0310"
+        );
+        assert_eq!(cpu.disassemble(0x0303).text, "LDA #$01");
+        assert_eq!(cpu.disassemble(0x0305).text, "*JAM");
+        let addresses: Vec<u16> = cpu
+            .disassemble_around(0x0303, 1, 1)
+            .iter()
+            .map(|line| line.address)
+            .collect();
+        assert_eq!(addresses, [0x0300, 0x0303, 0x0305]);
+
+        // BRK is followed by a signature byte that the CPU skips
+        let mut debugger = program();
+        debugger.execute("poke $0305 $00 $FF $E8").unwrap();
+        let cpu = &debugger.nes.cpu;
+        let lines = cpu.disassemble_range(0x0305, 2);
+        assert_eq!(lines[0].to_string(), "0305  00 FF     BRK #$FF");
+        assert_eq!(lines[0].next_address(), 0x0307);
+        assert_eq!(lines[1].text, "INX");
+    }
+
+    #[test]
+    fn wrapping_addresses() {
+        let mut debugger = program();
+        let cpu = &mut debugger.nes.cpu;
+        // The high byte of the IRQ vector, then the zero page
+        assert_eq!(
+            cpu.peek_u16(0xFFFF),
+            u16::from_le_bytes([cpu.peek(0xFFFF), cpu.peek(0x0000)])
+        );
+        let lines = cpu.disassemble_range(0xFFFF, 2);
+        assert_eq!(lines[0].address, 0xFFFF);
+        assert_eq!(lines[1].address, lines[0].next_address());
+        let lines = cpu.disassemble_around(0x0001, 4, 1);
+        assert!(lines[0].address > 0xFFF0);
+        assert!(lines.iter().any(|line| line.address == 0x0001));
+
+        cpu.pc = 0xFFFF;
+        cpu.enable_logging(true);
+        cpu.disassemble_and_log_current_instruction();
+        assert!(cpu.logs.starts_with("FFFF  "));
+
+        assert!(debugger
+            .execute("dis $FFFF 3")
+            .unwrap()
+            .starts_with("=> FFFF  "));
+        assert_eq!(debugger.execute("x $FFFF 2").unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut debugger = program();
+        debugger.step();
+        assert_eq!(
+            debugger.backtrace(),
+            [Frame {
+                kind: FrameKind::Subroutine,
+                call_site: 0x0300,
+                entry: 0x0310,
+                s: 0xFD,
+            }]
+        );
+        debugger.step();
+        assert_eq!(
+            debugger.execute("bt").unwrap(),
+            "#0   $0320 in $0320\n#1   $0310 in $0310\n#2   $0300"
+        );
+        assert_eq!(
+            debugger.execute("finish").unwrap(),
+            "=> 0313  60        RTS"
+        );
+        assert_eq!(debugger.backtrace().len(), 1);
+        assert_eq!(debugger.nes.cpu.x, 1);
+        debugger.execute("finish").unwrap();
+        assert_eq!(debugger.nes.cpu.pc, 0x0303);
+        assert_eq!(debugger.execute("finish"), Err(CommandError::NoFrame));
+
+        // Next steps over the subroutines
+        let mut debugger = program();
+        debugger.execute("next").unwrap();
+        assert_eq!(debugger.nes.cpu.pc, 0x0303);
+        assert_eq!(debugger.nes.cpu.x, 1);
+        assert!(debugger.backtrace().is_empty());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = program();
+        assert_eq!(
+            debugger.execute("b $0320 if x == 0").unwrap(),
+            "Breakpoint 0 at $0320"
+        );
+        assert_eq!(
+            debugger.execute("watch $0300-$03FF").unwrap(),
+            "Watchpoint 1 on writes to $0300-$03FF"
+        );
+        assert_eq!(
+            debugger.execute("c").unwrap(),
+            "Breakpoint 0 at $0320\n=> 0320  E8        INX"
+        );
+        assert_eq!(
+            debugger.execute("info").unwrap(),
+            "0   enabled  execution of $0320, hits: 1, if x == 0\n\
+             1   enabled  writes to $0300-$03FF, hits: 0"
+        );
+        assert_eq!(
+            debugger.execute("c").unwrap(),
+            "CPU jammed by opcode $02 at $0305\n=> 0305  02        *JAM"
+        );
+
+        // Watchpoints
+        debugger.reset();
+        debugger.nes.cpu.pc = 0x0300;
+        debugger.execute("poke $0306 $8D $00 $03").unwrap();
+        debugger.execute("poke $0305 $EA").unwrap();
+        debugger.execute("disable 0").unwrap();
+        assert_eq!(
+            debugger.execute("c").unwrap(),
+            "Watchpoint 1: wrote $01 to $0300\n=> 0309  00 00     BRK #$00"
+        );
+
+        assert_eq!(
+            debugger.execute("ignore 0 2").unwrap(),
+            "Ignoring the next 2 hits of breakpoint 0"
+        );
+        assert_eq!(debugger.nes.cpu.breakpoints.get(0).unwrap().ignore_count, 3);
+        debugger.execute("delete").unwrap();
+        assert_eq!(debugger.execute("info").unwrap(), "No breakpoints");
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut debugger = program();
+        debugger.execute("set a $42").unwrap();
+        debugger.execute("set c 1").unwrap();
+        assert_eq!(
+            debugger.execute("regs").unwrap(),
+            "A:42 X:00 Y:00 S:FD PC:0300 P:25 nv-bdIzC CYC:7"
+        );
+        debugger.execute("poke $0400 $41 $42").unwrap();
+        assert_eq!(
+            debugger.execute("x $0400 2").unwrap(),
+            format!("0400  41 42{}  |AB|", " ".repeat(42))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut debugger = program();
+        assert_eq!(
+            debugger.execute("jump"),
+            Err(CommandError::UnknownCommand("jump".to_string()))
+        );
+        assert_eq!(
+            debugger.execute("x"),
+            Err(CommandError::MissingArgument("address"))
+        );
+        assert_eq!(
+            debugger.execute("b C000"),
+            Err(CommandError::InvalidNumber("C000".to_string()))
+        );
+        assert_eq!(
+            debugger.execute("watch 3000-2000"),
+            Err(CommandError::InvalidRange("3000-2000".to_string()))
+        );
+        assert!(debugger.nes.cpu.breakpoints.is_empty());
+        assert_eq!(
+            debugger.execute("set w 1"),
+            Err(CommandError::UnknownRegister("w".to_string()))
+        );
+        assert_eq!(
+            debugger.execute("delete 3"),
+            Err(CommandError::NoBreakpoint(3))
+        );
+        assert!(matches!(
+            debugger.execute("b 1 if a ="),
+            Err(CommandError::InvalidCondition(_))
+        ));
+        assert_eq!(debugger.execute("   ").unwrap(), "");
+    }
+
+    #[test]
+    fn catch_brk() {
+        let mut debugger = program();
+        debugger.execute("poke $0305 $00").unwrap();
+        assert_eq!(debugger.execute("catch brk").unwrap(), "Stopping on BRK");
+        assert!(debugger.nes.cpu.break_on_brk);
+        let output = debugger.execute("c").unwrap();
+        assert!(output.starts_with("BRK at $0305\n"));
+        // The whole BRK sequence has run
+        assert_eq!(debugger.nes.cpu.pc, debugger.nes.cpu.peek_u16(0xFFFE));
+
+        assert_eq!(
+            debugger.execute("catch brk off").unwrap(),
+            "Not stopping on BRK"
+        );
+        assert!(!debugger.nes.cpu.break_on_brk);
+        assert_eq!(
+            debugger.execute("catch"),
+            Err(CommandError::MissingArgument("event"))
+        );
+        assert_eq!(
+            debugger.execute("catch nmi"),
+            Err(CommandError::UnknownCommand("catch nmi".to_string()))
+        );
+    }
+
+    #[test]
+    fn frames() {
+        let mut debugger = program();
+        // Like step 0, nothing runs
+        assert_eq!(
+            debugger.execute("frame 0").unwrap(),
+            "=> 0300  20 10 03  JSR $0310"
+        );
+        assert_eq!(debugger.nes.cpu.cycles, 7);
+
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut debugger = Debugger::new(Nes::new(cartridge));
+        debugger.execute("frame 2").unwrap();
+        // From the reset, the second frame ends at the start of the second VBlank
+        assert_eq!(debugger.nes.cpu.bus.ppu.scanline, 241);
+    }
+}
+
+#[cfg(test)]
+mod gdb {
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::cartridge::Cartridge;
+    use crate::gdb::GdbStub;
+    use crate::nes::Nes;
+
+    /// The client side of the protocol, acknowledging every packet.
+    struct Client(TcpStream);
+
+    impl Client {
+        fn write_packet(&mut self, data: &str) {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.0, "${}#{:02x}", data, checksum).unwrap();
+        }
+
+        fn read_packet(&mut self) -> String {
+            let mut byte = [0];
+            // Skips the acks
+            while byte[0] != b'$' {
+                self.0.read_exact(&mut byte).unwrap();
+            }
+            let mut data = Vec::new();
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+
+        fn send(&mut self, data: &str) -> String {
+            self.write_packet(data);
+            self.read_packet()
+        }
+    }
+
+    /// Serves `script` with JSR $0310; LDA #$01; STA $0400; JMP $0308 and INX; RTS at $0310.
+    fn session<F>(script: F) -> Nes
+    where
+        F: FnOnce(&mut Client) + Send + 'static,
+    {
+        let (nes, result) = serve(script);
+        result.unwrap();
+        nes
+    }
+
+    fn serve<F>(script: F) -> (Nes, io::Result<()>)
+    where
+        F: FnOnce(&mut Client) + Send + 'static,
+    {
+        let cartridge = Cartridge::from_file("misc/nestest.nes").unwrap();
+        let mut nes = Nes::new(cartridge);
+        let ram = &mut nes.cpu.bus.ram;
+        ram[0x0300..0x030B].copy_from_slice(&[
+            0x20, 0x10, 0x03, 0xA9, 0x01, 0x8D, 0x00, 0x04, 0x4C, 0x08, 0x03,
+        ]);
+        ram[0x0310..0x0312].copy_from_slice(&[0xE8, 0x60]);
+        nes.cpu.pc = 0x0300;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client(stream);
+            script(&mut client);
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let result = GdbStub::new(&mut nes, stream).run();
+        client.join().unwrap();
+        (nes, result)
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let nes = session(|client| {
+            let features = client.send("qSupported:swbreak+");
+            assert!(features.starts_with("PacketSize=1000;") && features.contains("swbreak+"));
+            assert_eq!(client.send("?"), "S05");
+            assert_eq!(client.send("g"), "00000024fd0003");
+            assert_eq!(client.send("P0=42"), "OK");
+            assert_eq!(client.send("p0"), "42");
+            assert_eq!(client.send("P5=1003"), "OK");
+            assert_eq!(client.send("p5"), "1003");
+            assert_eq!(client.send("G010203a5fc0803"), "OK");
+            assert_eq!(client.send("g"), "010203a5fc0803");
+            assert_eq!(client.send("G0102"), "E01");
+            assert_eq!(client.send("p6"), "E01");
+
+            assert_eq!(client.send("m0300,3"), "201003");
+            // Any byte can be escaped, the checksum covers the escape
+            assert_eq!(client.send("}M0300,3"), "201003");
+            assert_eq!(client.send("M0400,2:4142"), "OK");
+            assert_eq!(client.send("m0400,2"), "4142");
+            assert_eq!(client.send("M0400,2:41"), "E01");
+            // Clamped to what fits in a packet
+            assert_eq!(client.send("m0,ffffffff").len(), 0x1000);
+            assert_eq!(client.send("m0,1ffffffff"), "E01");
+
+            assert_eq!(
+                client.send("qXfer:features:read:target.xml:0,f"),
+                r#"m<?xml version=""#
+            );
+            let xml = client.send("qXfer:features:read:target.xml:0,fff");
+            assert!(xml.starts_with("l<?xml"));
+            assert!(xml.contains(r#"<reg name="pc" bitsize="16""#));
+            assert_eq!(client.send("vMustReplyEmpty"), "");
+            assert_eq!(client.send("D"), "OK");
+        });
+        assert_eq!(nes.cpu.a, 0x01);
+        assert_eq!(nes.cpu.pc, 0x0308);
+        assert_eq!(nes.cpu.bus.ram[0x0400], 0x41);
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let nes = session(|client| {
+            assert_eq!(client.send("QStartNoAckMode"), "OK");
+            assert_eq!(client.send("Z0,310,1"), "OK");
+            assert_eq!(client.send("c"), "T05swbreak:;");
+            assert_eq!(client.send("p5"), "1003");
+            assert_eq!(client.send("s"), "S05");
+            assert_eq!(client.send("p1"), "01");
+            assert_eq!(client.send("z0,310,1"), "OK");
+            assert_eq!(client.send("z0,310,1"), "E01");
+
+            assert_eq!(client.send("Z2,400,1"), "OK");
+            assert_eq!(client.send("c"), "T05watch:400;");
+            assert_eq!(client.send("m0400,1"), "01");
+            assert_eq!(client.send("p5"), "0803");
+            assert_eq!(client.send("z2,400,1"), "OK");
+            assert_eq!(client.send("Z4,400,4"), "OK");
+            assert_eq!(client.send("c0300"), "T05awatch:400;");
+            assert_eq!(client.send("z4,400,4"), "OK");
+
+            // The whole address space, and past it
+            assert_eq!(client.send("Z2,0,10000"), "OK");
+            assert_eq!(client.send("z2,0,10000"), "OK");
+            assert_eq!(client.send("Z2,fff0,20"), "OK");
+            assert_eq!(client.send("z2,fff0,20"), "OK");
+            assert_eq!(client.send("Z2,0,10001"), "E01");
+            assert_eq!(client.send("Z2,10000,1"), "E01");
+            assert_eq!(client.send("Z4,400,4"), "OK");
+
+            // Ctrl-C while running the JMP loop
+            client.write_packet("c");
+            thread::sleep(Duration::from_millis(50));
+            client.0.write_all(&[0x03]).unwrap();
+            assert_eq!(client.read_packet(), "S02");
+
+            assert_eq!(client.send("M0308,1:02"), "OK");
+            assert_eq!(client.send("c"), "S04");
+            client.write_packet("k");
+        });
+        // The breakpoints of the session are gone
+        assert!(nes.cpu.breakpoints.is_empty());
+        assert_eq!(nes.cpu.pc, 0x0308);
+    }
+
+    #[test]
+    fn disconnect_while_running() {
+        let (nes, result) = serve(|client| {
+            // Without acks, nothing is written to the closed stream
+            assert_eq!(client.send("QStartNoAckMode"), "OK");
+            assert_eq!(client.send("Z2,500,1"), "OK");
+            // The stream is closed while the JMP loop runs
+            client.write_packet("c");
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert!(nes.cpu.breakpoints.is_empty());
+    }
 }
@@ -0,0 +1,170 @@
+use crate::cartridge::{CartridgeError, Mirroring};
+use crate::header::{ConsoleType, HeaderFormat, RomHeader, Timing};
+
+/// A cartridge in the UNIF format, converted to the header the iNES loader would produce.
+/// See https://wiki.nesdev.com/w/index.php/UNIF
+#[derive(Debug)]
+pub struct Unif {
+    /// Board name from the MAPR chunk, e.g. "NES-SLROM"
+    pub board: String,
+    pub header: RomHeader,
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+}
+
+impl Unif {
+    pub const MAGIC: &'static [u8] = b"UNIF";
+    /// Magic, revision and reserved bytes
+    pub const HEADER_SIZE: usize = 32;
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        if !bytes.starts_with(Self::MAGIC) {
+            return Err(CartridgeError::BadMagic);
+        }
+        if bytes.len() < Self::HEADER_SIZE {
+            return Err(CartridgeError::TruncatedHeader);
+        }
+
+        let mut board = None;
+        let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+        let mut mirroring = Mirroring::Horizontal;
+        let mut has_battery = false;
+        let mut timing = Timing::Ntsc;
+
+        let mut data = &bytes[Self::HEADER_SIZE..];
+        while !data.is_empty() {
+            if data.len() < 8 {
+                return Err(CartridgeError::TruncatedChunk(
+                    String::from_utf8_lossy(&data[..data.len().min(4)]).into_owned(),
+                ));
+            }
+            let id = &data[..4];
+            let length = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+            let chunk = data[8..]
+                .get(..length)
+                .ok_or_else(|| CartridgeError::TruncatedChunk(chunk_name(id)))?;
+            data = &data[8 + length..];
+
+            match id {
+                b"MAPR" => {
+                    // Null terminated
+                    let name = chunk.split(|&byte| byte == 0).next().unwrap_or(&[]);
+                    board = Some(String::from_utf8_lossy(name).into_owned());
+                }
+                [b'P', b'R', b'G', n] => {
+                    if let Some(index) = chunk_index(*n) {
+                        prg_chunks[index] = Some(chunk);
+                    }
+                }
+                [b'C', b'H', b'R', n] => {
+                    if let Some(index) = chunk_index(*n) {
+                        chr_chunks[index] = Some(chunk);
+                    }
+                }
+                b"MIRR" => {
+                    mirroring = match chunk.first() {
+                        Some(1) => Mirroring::Vertical,
+                        Some(2) => Mirroring::SingleScreenA,
+                        Some(3) => Mirroring::SingleScreenB,
+                        Some(4) => Mirroring::FourScreen,
+                        // 0 is hardwired horizontal, 5 is controlled by the mapper
+                        _ => Mirroring::Horizontal,
+                    }
+                }
+                b"BATR" => has_battery = true,
+                b"TVCI" => {
+                    timing = match chunk.first() {
+                        Some(1) => Timing::Pal,
+                        Some(2) => Timing::Multi,
+                        _ => Timing::Ntsc,
+                    }
+                }
+                // Checksums, names, dumper info...
+                _ => {}
+            }
+        }
+
+        let board = board.ok_or(CartridgeError::MissingChunk("MAPR"))?;
+        let prg_rom: Vec<u8> = prg_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+        let chr_rom: Vec<u8> = chr_chunks
+            .iter()
+            .flatten()
+            .flat_map(|c| c.iter())
+            .copied()
+            .collect();
+        if prg_rom.is_empty() {
+            return Err(CartridgeError::MissingPrgRom);
+        }
+
+        let (mapper, submapper, prg_ram_units) =
+            board_mapper(&board).ok_or_else(|| CartridgeError::UnsupportedBoard(board.clone()))?;
+
+        let header = RomHeader {
+            format: HeaderFormat::Unif,
+            prg_rom_size: prg_rom.len(),
+            chr_rom_size: chr_rom.len(),
+            mapper,
+            submapper,
+            mirroring,
+            has_battery,
+            has_trainer: false,
+            prg_ram_shift: 0,
+            prg_nvram_shift: 0,
+            chr_ram_shift: 0,
+            chr_nvram_shift: 0,
+            ines_prg_ram_units: prg_ram_units,
+            timing,
+            console_type: ConsoleType::Nes,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+        };
+
+        Ok(Self {
+            board,
+            header,
+            prg_rom,
+            chr_rom,
+        })
+    }
+}
+
+fn chunk_name(id: &[u8]) -> String {
+    String::from_utf8_lossy(id).into_owned()
+}
+
+/// PRG0-PRGF and CHR0-CHRF are numbered with a hexadecimal digit.
+fn chunk_index(digit: u8) -> Option<usize> {
+    (digit as char).to_digit(16).map(|n| n as usize)
+}
+
+/// iNES mapper, submapper and PRG RAM size in 8 KiB units of a UNIF board name.
+pub fn board_mapper(board: &str) -> Option<(u16, u8, u8)> {
+    // Prefixes tell who made the board, e.g. NES-SLROM and HVC-SLROM are the same
+    let name = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-"]
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(board);
+
+    let mapper = match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => (0, 0, 1),
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" | "SKROM"
+        | "SLROM" | "SL1ROM" | "SNROM" | "SUROM" => (1, 0, 1),
+        "SOROM" => (1, 0, 2),
+        "SXROM" => (1, 0, 4),
+        "UNROM" | "UOROM" => (2, 0, 1),
+        "CNROM" => (3, 0, 1),
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TNROM"
+        | "TR1ROM" | "TSROM" | "TVROM" => (4, 0, 1),
+        // NES 2.0 submapper 2 has bus conflicts like AMROM and AOROM, 1 doesn't like ANROM
+        "AMROM" | "AOROM" => (7, 2, 1),
+        "ANROM" | "AN1ROM" => (7, 1, 1),
+        _ => return None,
+    };
+    Some(mapper)
+}